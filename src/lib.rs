@@ -7,224 +7,5117 @@
 //! # Features
 //! - Simple API.
 //! - Provides a Cell like struct powered by a Mutex: [`MutexCell`](struct.MutexCell.html).
+//! - Provides a read-write lock counterpart for read-heavy data: [`WithRwLock`](struct.WithRwLock.html).
+//! - Provides a lock that the same thread can re-enter: [`ReentrantWithLock`](struct.ReentrantWithLock.html).
+//! - Locks several values at once without risking lock-order deadlocks: [`lock2`](fn.lock2.html) and [`lock_many`](fn.lock_many.html).
+//! - A [`with_lock!`](macro.with_lock.html) macro that locks one or two `WithLock`s or `MutexCell`s.
+//!
+//! # Optional features
+//! - `std` (enabled by default): items that need the standard library, such as timeouts and [`ReentrantWithLock`](struct.ReentrantWithLock.html).
+//!   Without it the crate is `no_std` and uses spin locks from [spin](https://crates.io/crates/spin).
+//! - `parking-lot` (enabled by default): locks are backed by [parking_lot](https://crates.io/crates/parking_lot). Implies `std`.
+//!   With `std` alone they are backed by [`std::sync`](https://doc.rust-lang.org/std/sync/) instead, ignoring poisoning like `parking_lot` does.
+//!   The API is the same with either backend.
+//! - `async`: provides [`AsyncWithLock`](struct.AsyncWithLock.html), backed by [tokio](https://crates.io/crates/tokio)'s mutex.
+//! - `log`: provides [`WithLock::with_lock_warn`](struct.WithLock.html#method.with_lock_warn), which logs a warning when a lock is held for too long. Implies `std`.
+//! - `tracing`: [`WithLock::with_lock`](struct.WithLock.html#method.with_lock) records a `with_lock` span, with the locked type and the time spent waiting for the lock (`wait_us`).
+//!   The span closes once the lock has been released. Implies `std`.
+//! - `metrics`: each [`WithLock`](struct.WithLock.html) counts how often it was contended and how long it was held, see [`WithLock::contention_count`](struct.WithLock.html#method.contention_count)
+//!   and [`WithLock::hold_stats`](struct.WithLock.html#method.hold_stats). Requires `std`.
+//! - `deadlock-detection`: enables `parking_lot`'s deadlock detector and provides [`spawn_deadlock_checker`](fn.spawn_deadlock_checker.html) to report deadlocks. Implies `parking-lot`.
+//! - `serde`: implements `Serialize` and `Deserialize` for [`WithLock`](struct.WithLock.html) and [`MutexCell`](struct.MutexCell.html).
 //!
 //! # Caveats
 //! If you manage to find a deadlock, please report it [here](https://github.com/Milo123459/with_lock/issues).
 //!
 //! This snippet would deadlock: `s.with_Lock(|test| s.with_lock(|test2| test2))`
 
-use parking_lot::{const_mutex, Mutex};
-use std::mem;
-use std::ptr;
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+// Defines a `const fn`, except in loom test builds, where the loom primitives it builds can't be created in a const context.
+macro_rules! const_fn {
+	($(#[$attr:meta])* $vis:vis const fn $($rest:tt)*) => {
+		#[cfg(not(all(loom, test)))]
+		$(#[$attr])*
+		$vis const fn $($rest)*
+
+		#[cfg(all(loom, test))]
+		$(#[$attr])*
+		$vis fn $($rest)*
+	};
+}
+
+mod sys;
+
+#[cfg(feature = "std")]
+use core::borrow::Borrow;
+use core::cmp;
+use core::fmt;
+#[cfg(feature = "std")]
+use core::hash::BuildHasher;
+use core::hash::{Hash, Hasher};
+use core::hint;
+#[cfg(feature = "std")]
+use core::marker::PhantomData;
+use core::mem;
+use core::ops::{Add, AddAssign, Deref, DerefMut, DivAssign, Mul, MulAssign, Sub, SubAssign};
+use core::ptr;
+#[cfg(all(
+	any(feature = "metrics", target_has_atomic = "64"),
+	not(all(loom, test))
+))]
+use core::sync::atomic::AtomicU64;
+#[cfg(all(not(target_has_atomic = "64"), not(all(loom, test))))]
+use core::sync::atomic::AtomicUsize;
+use core::sync::atomic::Ordering;
+#[cfg(all(feature = "std", not(all(loom, test))))]
+use core::sync::atomic::{AtomicBool, AtomicPtr};
+use core::time::Duration;
+#[cfg(all(any(feature = "metrics", target_has_atomic = "64"), loom, test))]
+use loom::sync::atomic::AtomicU64;
+#[cfg(all(not(target_has_atomic = "64"), loom, test))]
+use loom::sync::atomic::AtomicUsize;
+#[cfg(all(loom, test))]
+use loom::sync::atomic::{AtomicBool, AtomicPtr};
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "std")]
+use std::sync::Arc;
+#[cfg(feature = "std")]
+use std::time::Instant;
+use sys::{
+	const_mutex, const_rwlock, Mutex, MutexGuard, RwLock, RwLockUpgradableReadGuard,
+	RwLockWriteGuard,
+};
+#[cfg(feature = "std")]
+use sys::{const_reentrant_mutex, Condvar, ReentrantMutex};
 
 pub struct WithLock<T> {
 	pub(crate) data: Mutex<T>,
+	#[cfg(feature = "std")]
+	pub(crate) poisoned: AtomicBool,
+	#[cfg(feature = "std")]
+	pub(crate) condvar: Condvar,
+	#[cfg(feature = "metrics")]
+	pub(crate) contention: AtomicU64,
+	#[cfg(feature = "metrics")]
+	pub(crate) hold: HoldStats,
 }
 
 impl<T> WithLock<T> {
 	/// Returns what `Mutex.lock()` would.
 	///
 	/// If you clone the value inside the function provided, everything touching the value will have to be inside the function.
+	///
+	/// To reuse a closure (and its captured state) across calls, pass it by mutable reference.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::<i64>::new(0);
+	/// let mut calls = 0;
+	/// let mut increment = |s: &mut i64| {
+	///     calls += 1;
+	///     *s += 1;
+	/// };
+	/// lock.with_lock(&mut increment);
+	/// lock.with_lock(&mut increment);
+	/// assert_eq!(calls, 2);
+	/// ```
 	pub fn with_lock<F, U>(&self, function: F) -> U
 	where
 		F: FnOnce(&mut T) -> U,
 	{
-		let mut lock = self.data.lock();
+		#[cfg(feature = "tracing")]
+		let span = tracing::trace_span!(
+			"with_lock",
+			r#type = core::any::type_name::<T>(),
+			wait_us = tracing::field::Empty
+		);
+		#[cfg(feature = "tracing")]
+		let _entered = span.enter();
+		#[cfg(feature = "tracing")]
+		let start = Instant::now();
+		let mut lock = self.acquire();
+		#[cfg(feature = "tracing")]
+		span.record("wait_us", start.elapsed().as_micros() as u64);
 		function(&mut *lock)
 	}
 
-	/// Create a new `WithLock` instance.
+	/// Like [`with_lock`](WithLock::with_lock), but only hands out a shared reference.
+	///
+	/// The lock is still exclusive; this just states that the function only reads.
+	/// For data that is mostly read from many threads at once, see [`WithRwLock::read_with`].
 	/// ## Examples
 	/// ```rust
 	/// use with_lock::WithLock;
-	/// WithLock::<i64>::new(123);
+	/// let lock = WithLock::new(String::from("hello"));
+	/// assert_eq!(lock.with_lock_ref(String::len), 5);
 	/// ```
-	pub fn new<F>(data: F) -> WithLock<F> {
-		WithLock {
-			data: const_mutex(data),
-		}
+	pub fn with_lock_ref<F, U>(&self, function: F) -> U
+	where
+		F: FnOnce(&T) -> U,
+	{
+		self.with_lock(|s| function(s))
 	}
-}
 
-pub struct MutexCell<T> {
-	pub(crate) data: WithLock<T>,
-}
+	/// Like [`with_lock`](WithLock::with_lock), but never blocks.
+	///
+	/// Returns `None` without running the function if the lock is currently held elsewhere.
+	pub fn try_with_lock<F, U>(&self, function: F) -> Option<U>
+	where
+		F: FnOnce(&mut T) -> U,
+	{
+		let mut lock = self.try_acquire()?;
+		Some(function(&mut *lock))
+	}
 
-impl<T> MutexCell<T> {
-	/// Create a new MutexCell with a value.
-	/// ## Example
+	/// Runs a fallible function with the lock held, forwarding its `Result`.
+	///
+	/// This is the canonical form for functions that can fail: the lock is always released before the result is returned, so an error can be propagated with `?` without keeping the lock held.
+	/// ## Examples
 	/// ```rust
-	/// use with_lock::MutexCell;
-	/// let mutex = MutexCell::new(23);
-	/// assert_eq!(mutex.get(), 23)
+	/// use with_lock::WithLock;
+	/// fn parse_into(lock: &WithLock<i64>, input: &str) -> Result<i64, std::num::ParseIntError> {
+	///     let value = lock.try_with_lock_fallible(|s| {
+	///         *s = input.parse()?;
+	///         Ok(*s)
+	///     })?;
+	///     Ok(value * 2)
+	/// }
+	/// let lock = WithLock::<i64>::new(0);
+	/// assert_eq!(parse_into(&lock, "21"), Ok(42));
+	/// assert!(parse_into(&lock, "nope").is_err());
+	/// ```
+	pub fn try_with_lock_fallible<F, U, E>(&self, function: F) -> Result<U, E>
+	where
+		F: FnOnce(&mut T) -> Result<U, E>,
+	{
+		self.with_lock(function)
+	}
+
+	/// Like [`with_lock`](WithLock::with_lock), but gives up after waiting for `timeout`.
+	///
+	/// Returns `None` without running the function if the lock could not be acquired in time.
+	/// A zero `timeout` makes a single attempt, like [`try_with_lock`](WithLock::try_with_lock).
+	#[cfg(feature = "std")]
+	pub fn with_lock_timeout<F, U>(&self, timeout: Duration, function: F) -> Option<U>
+	where
+		F: FnOnce(&mut T) -> U,
+	{
+		let mut lock = self.acquire_for(timeout)?;
+		Some(function(&mut *lock))
+	}
+
+	/// Like [`with_lock`](WithLock::with_lock), but spins for a while before blocking.
+	///
+	/// Tries to take the lock up to `spins` times, with a [`spin_loop`](core::hint::spin_loop) hint between attempts,
+	/// then falls back to blocking. For very short critical sections under light contention, this can beat going to sleep.
+	/// The function always runs exactly once.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::<i64>::new(1);
+	/// assert_eq!(lock.with_lock_spin(100, |s| *s + 1), 2);
 	/// ```
-	pub fn new(data: T) -> MutexCell<T> {
-		MutexCell {
-			data: WithLock::<T>::new(data),
+	pub fn with_lock_spin<F, U>(&self, spins: u32, function: F) -> U
+	where
+		F: FnOnce(&mut T) -> U,
+	{
+		for _ in 0..spins {
+			if let Some(mut lock) = self.try_acquire() {
+				return function(&mut *lock);
+			}
+			hint::spin_loop();
 		}
+		let mut lock = self.acquire();
+		function(&mut *lock)
 	}
 
-	/// Returns a copy of the contained value.
-	pub fn get(&self) -> T
+	/// Like [`with_lock`](WithLock::with_lock), but unlocks fairly afterwards.
+	///
+	/// Normally a thread that unlocks and immediately locks again can barge ahead of threads already waiting, which is fast but can starve them.
+	/// A fair unlock hands the lock straight to the next waiting thread instead, trading throughput for predictable latency.
+	/// With the `parking-lot` feature disabled there is no fair unlock, and this behaves like `with_lock`.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::<i64>::new(1);
+	/// assert_eq!(lock.with_lock_fair(|s| *s + 1), 2);
+	/// ```
+	pub fn with_lock_fair<F, U>(&self, function: F) -> U
 	where
-		T: Copy,
+		F: FnOnce(&mut T) -> U,
 	{
-		self.data.with_lock(|s| *s)
+		let mut lock = self.acquire();
+		let result = function(&mut *lock);
+		let Held { guard, .. } = lock;
+		sys::unlock_fair(guard);
+		result
 	}
 
-	/// Returns a mutable reference to the underlying data.
-	pub fn get_mut(&mut self) -> &mut T
+	/// Locks this and another `WithLock` together and runs the function with both values.
+	///
+	/// This is the method form of [`lock2`]: the locks are taken in a consistent order, so opposite-order calls cannot deadlock.
+	///
+	/// # Panics
+	/// Panics if `other` is this same lock.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let hits = WithLock::<u64>::new(3);
+	/// let misses = WithLock::<u64>::new(1);
+	/// assert_eq!(hits.with_two(&misses, |hits, misses| *hits + *misses), 4);
+	/// ```
+	pub fn with_two<B, F, R>(&self, other: &WithLock<B>, function: F) -> R
 	where
-		T: Copy,
+		F: FnOnce(&mut T, &mut B) -> R,
 	{
-		self.data.data.get_mut()
+		lock2(self, other, function)
 	}
 
-	/// Sets the contained value.
-	pub fn set(&self, data: T) {
-		self.data.with_lock(|s| *s = data);
+	/// Locks the mutex and returns a guard that unlocks it when dropped.
+	///
+	/// Prefer [`with_lock`](WithLock::with_lock) where possible.
+	/// Holding the guard while calling `with_lock` (or `lock`) on the same `WithLock` will deadlock.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::<i64>::new(1);
+	/// {
+	///     let mut guard = lock.lock();
+	///     *guard += 1;
+	/// }
+	/// assert_eq!(lock.with_lock(|s| *s), 2);
+	/// ```
+	pub fn lock(&self) -> WithLockGuard<'_, T> {
+		WithLockGuard {
+			guard: self.acquire(),
+		}
 	}
 
-	/// Replaces the contained value with `val`, and returns the old contained value.
-	pub fn replace(&self, val: T) -> T {
-		self.data.with_lock(|old| mem::replace(old, val))
+	/// Like [`lock`](WithLock::lock), but never blocks.
+	///
+	/// Returns `None` if the lock is currently held elsewhere.
+	pub fn try_lock(&self) -> Option<WithLockGuard<'_, T>> {
+		self.try_acquire().map(|guard| WithLockGuard { guard })
 	}
 
-	/// Swaps the values of two `MutexCell`s.
-	pub fn swap(&self, new: &MutexCell<T>) {
-		if ptr::eq(self, new) {
-			return;
+	/// Locks the mutex and returns a guard that keeps a clone of the [`Arc`] alive, so it is not tied to a borrow.
+	///
+	/// The guard is `'static`, which lets it be stored in long-lived structures or kept by a spawned thread that owns its `Arc`.
+	/// Like [`WithLockGuard`], it can't be sent to another thread while the lock is held.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::new_shared(1);
+	/// let mut guard = lock.lock_owned();
+	/// drop(lock);
+	/// *guard += 1;
+	/// assert_eq!(*guard, 2);
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn lock_owned(self: &Arc<Self>) -> ArcWithLockGuard<T>
+	where
+		T: 'static,
+	{
+		let lock = Arc::clone(self);
+		// SAFETY: the guard is dropped before `lock`, so the `WithLock` outlives the borrow.
+		let this: &'static WithLock<T> = unsafe { &*Arc::as_ptr(&lock) };
+		ArcWithLockGuard {
+			guard: this.acquire(),
+			lock,
 		}
-		self.data
-			.with_lock(|a| new.data.with_lock(|b| mem::swap(a, b)))
 	}
 
-	/// Takes the value of the cell, leaving `Default::default()` in its place.
-	pub fn take(&self) -> T
+	/// Runs the function with the contained value, holding a clone of the [`Arc`] for as long as the lock is held.
+	///
+	/// This is [`lock_owned`](WithLock::lock_owned) for when the guard doesn't need to outlive one call.
+	#[cfg(feature = "std")]
+	pub fn with_lock_owned<F, U>(self: &Arc<Self>, function: F) -> U
 	where
-		T: Default,
+		F: FnOnce(&mut T) -> U,
+		T: 'static,
 	{
-		self.replace(T::default())
+		function(&mut self.lock_owned())
 	}
 
-	/// Unwraps the value.
-	pub fn into_inner(self) -> T {
-		self.data.data.into_inner()
+	/// Returns an accessor that locks this `WithLock` and hands out only the part chosen by `selector`.
+	///
+	/// The projection shares this lock, so it is about ergonomics rather than finer-grained locking.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::new((1, String::from("a")));
+	/// let name = lock.project(|s| &mut s.1);
+	/// name.with_lock(|name| name.push('b'));
+	/// assert_eq!(lock.with_lock(|s| s.clone()), (1, String::from("ab")));
+	/// ```
+	pub fn project<U, F>(&self, selector: F) -> Projection<'_, T, F>
+	where
+		F: Fn(&mut T) -> &mut U,
+	{
+		Projection {
+			lock: self,
+			selector,
+		}
 	}
-}
 
-#[cfg(doctest)]
-#[doc = include_str!("../README.md")]
-mod readme {}
+	/// Returns whether the lock is currently held.
+	///
+	/// The answer may be stale by the time it is returned, so only use it for metrics and diagnostics, never to decide whether locking is safe.
+	#[must_use]
+	pub fn is_locked(&self) -> bool {
+		self.data.is_locked()
+	}
 
-#[cfg(test)]
-mod tests {
-	use crate::*;
+	/// Returns a mutable reference to the underlying data.
+	///
+	/// No locking is needed, since the mutable borrow guarantees there are no other users.
+	pub fn get_mut(&mut self) -> &mut T {
+		self.data.get_mut()
+	}
 
-	struct SharedData {
-		pub a: i64,
-		pub b: i64,
+	/// Replaces the contained value with `val`, and returns the old value, in one lock.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::<i64>::new(1);
+	/// assert_eq!(lock.replace(2), 1);
+	/// assert_eq!(lock.with_lock(|s| *s), 2);
+	/// ```
+	#[must_use = "if you don't need the old value, use `with_lock` to assign instead"]
+	pub fn replace(&self, val: T) -> T {
+		self.with_lock(|old| mem::replace(old, val))
 	}
 
-	#[test]
-	fn test_with_lock() {
-		let a = WithLock::<i64>::new(2);
-		let b = WithLock::<i64>::new(3);
+	/// Sets the contained value.
+	pub fn set(&self, val: T) {
+		self.with_lock(|s| *s = val)
+	}
 
-		let action_and_get = |s: &mut i64| *s;
-		let a_lock = a.with_lock(action_and_get);
-		let b_lock = b.with_lock(action_and_get);
-		assert_eq!(a_lock + b_lock, 5);
+	/// Consumes the `WithLock`, transforms the value, and wraps the result in a new `WithLock`.
+	///
+	/// No locking is needed, since `self` is owned. The new lock starts out fresh: not poisoned, and with no metrics recorded.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::<i32>::new(7);
+	/// let lock = lock.map(|n| n.to_string());
+	/// assert_eq!(lock.with_lock(|s| s.clone()), "7");
+	/// ```
+	pub fn map<U, F>(self, function: F) -> WithLock<U>
+	where
+		F: FnOnce(T) -> U,
+	{
+		WithLock::new(function(self.data.into_inner()))
+	}
 
-		// repeat action with embedded lambda expression
-		let a_lock_2 = a.with_lock(|s| *s);
-		let b_lock_2 = b.with_lock(|s| *s);
-		assert_eq!(a_lock_2 + b_lock_2, 5);
+	/// Takes the contained value, leaving `Default::default()` in its place.
+	#[must_use = "if you don't need the old value, use `set` instead"]
+	pub fn take(&self) -> T
+	where
+		T: Default,
+	{
+		self.replace(T::default())
 	}
 
-	#[test]
-	fn test_with_lock_over_struct() {
-		let a = WithLock::<SharedData>::new(SharedData { a: 2, b: 2 });
-		let b = WithLock::<SharedData>::new(SharedData { a: 3, b: 3 });
+	/// Create a new `WithLock` wrapped in an [`Arc`], ready to be shared between threads.
+	/// ## Examples
+	/// ```rust
+	/// use std::sync::Arc;
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::new_shared(1);
+	/// let other = Arc::clone(&lock);
+	/// std::thread::spawn(move || other.with_lock(|s| *s += 1)).join().unwrap();
+	/// assert_eq!(lock.with_lock(|s| *s), 2);
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn new_shared(data: T) -> Arc<WithLock<T>> {
+		Arc::new(WithLock::new(data))
+	}
 
-		let action_and_get = |s: &mut SharedData| (*s).a;
-		let a_lock = a.with_lock(action_and_get);
-		let b_lock = b.with_lock(action_and_get);
-		assert_eq!(a_lock + b_lock, 5);
+	/// Moves this `WithLock` into an [`Arc`], so an existing lock can be shared between threads.
+	#[cfg(feature = "std")]
+	pub fn into_arc(self) -> Arc<WithLock<T>> {
+		Arc::new(self)
+	}
 
-		// repeat action with embedded lambda expression and member b (avoid dead code warning)
-		let a_lock_2 = a.with_lock(|s| (*s).b);
-		let b_lock_2 = b.with_lock(|s| (*s).b);
-		assert_eq!(a_lock_2 + b_lock_2, 5);
+	const_fn! {
+		/// Create a new `WithLock` instance.
+		/// ## Examples
+		/// ```rust
+		/// use with_lock::WithLock;
+		/// WithLock::<i64>::new(123);
+		/// ```
+		pub const fn new(data: T) -> WithLock<T> {
+			WithLock::from_sys_mutex(const_mutex(data))
+		}
 	}
 
-	#[test]
-	fn test_mutex_cell_no_deadlocks() {
-		let a = MutexCell::new(2);
-		let b = MutexCell::new(3);
-		let a_lock = a.get();
-		let b_lock = b.get();
-		assert_eq!(a_lock + b_lock, 5);
-		let a_lock_2 = a.get();
-		let b_lock_2 = b.get();
-		assert_eq!(a_lock_2 + b_lock_2, 5);
+	/// Wraps an existing `parking_lot` mutex, keeping its current value.
+	///
+	/// Only available with the `parking-lot` feature, since the other backends use different mutex types.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let mutex = parking_lot::Mutex::new(1);
+	/// let lock = WithLock::from_mutex(mutex);
+	/// assert_eq!(lock.with_lock(|s| *s), 1);
+	/// ```
+	#[cfg(all(feature = "parking-lot", not(all(loom, test))))]
+	pub const fn from_mutex(mutex: parking_lot::Mutex<T>) -> WithLock<T> {
+		WithLock::from_sys_mutex(mutex)
 	}
 
-	#[test]
-	fn test_mutex_cell_mutability() {
-		let cell = MutexCell::new(3);
-		assert_eq!(cell.get(), 3);
-		cell.set(4);
-		assert_eq!(cell.get(), 4);
+	/// Unwraps the underlying `parking_lot` mutex, for handing it to code that expects one.
+	///
+	/// Only available with the `parking-lot` feature, since the other backends use different mutex types.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::<i64>::new(1);
+	/// let mutex = lock.into_mutex();
+	/// assert_eq!(*mutex.lock(), 1);
+	/// ```
+	#[cfg(all(feature = "parking-lot", not(all(loom, test))))]
+	#[must_use]
+	pub fn into_mutex(self) -> parking_lot::Mutex<T> {
+		self.data
 	}
 
-	#[test]
-	fn test_mutex_cell_replace() {
-		let cell = MutexCell::new(3);
-		assert_eq!(cell.replace(4), 3);
-		assert_eq!(cell.get(), 4);
+	const_fn! {
+		const fn from_sys_mutex(data: Mutex<T>) -> WithLock<T> {
+			WithLock {
+				data,
+				#[cfg(feature = "std")]
+				poisoned: AtomicBool::new(false),
+				#[cfg(feature = "std")]
+				condvar: Condvar::new(),
+				#[cfg(feature = "metrics")]
+				contention: AtomicU64::new(0),
+				#[cfg(feature = "metrics")]
+				hold: HoldStats {
+					count: AtomicU64::new(0),
+					nanos: AtomicU64::new(0),
+				},
+			}
+		}
 	}
 
-	#[test]
-	fn test_mutex_cell_swap() {
-		let c1 = MutexCell::new(5);
-		let c2 = MutexCell::new(10);
-		c1.swap(&c2);
-		assert_eq!(10, c1.get());
-		assert_eq!(5, c2.get());
+	/// Blocks until the mutex is locked, keeping track of contention when metrics are enabled.
+	fn acquire(&self) -> Held<'_, T> {
+		#[cfg(feature = "metrics")]
+		match self.data.try_lock() {
+			Some(lock) => return self.held(lock),
+			None => {
+				self.contention.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+		self.held(self.data.lock())
 	}
 
-	#[test]
-	fn test_mutex_cell_swap_doesnt_deadlock() {
-		let c1 = MutexCell::new(5);
-		assert_eq!(c1.get(), 5);
-		c1.swap(&c1);
-		assert_eq!(c1.get(), 5);
+	/// Waits up to `timeout` for the mutex, counting contention like [`acquire`](WithLock::acquire) does.
+	#[cfg(feature = "std")]
+	fn acquire_for(&self, timeout: Duration) -> Option<Held<'_, T>> {
+		#[cfg(feature = "metrics")]
+		match self.data.try_lock() {
+			Some(lock) => return Some(self.held(lock)),
+			None => {
+				self.contention.fetch_add(1, Ordering::Relaxed);
+			}
+		}
+		self.data.try_lock_for(timeout).map(|lock| self.held(lock))
 	}
 
-	#[test]
-	fn test_mutex_cell_get_mut() {
-		let mut c1 = MutexCell::new(5);
-		assert_eq!(c1.get(), 5);
-		let c2 = c1.get_mut();
-		*c2 += 1;
-		assert_eq!(c1.get(), 6);
+	/// Locks the mutex if it is free, without blocking.
+	fn try_acquire(&self) -> Option<Held<'_, T>> {
+		self.data.try_lock().map(|lock| self.held(lock))
 	}
 
-	#[test]
-	fn test_mutex_cell_take() {
-		let c = MutexCell::new(5);
-		let five = c.take();
+	fn held<'a>(&'a self, guard: MutexGuard<'a, T>) -> Held<'a, T> {
+		Held {
+			guard,
+			#[cfg(feature = "metrics")]
+			timer: HoldTimer {
+				stats: &self.hold,
+				since: Instant::now(),
+			},
+		}
+	}
 
-		assert_eq!(five, 5);
-		assert_eq!(c.into_inner(), 0);
+	/// Blocks until `predicate` returns `true` for the contained value.
+	///
+	/// The lock is released while waiting, and the predicate is checked again each time [`notify_all`](WithLock::notify_all) is called (or on a spurious wakeup).
+	/// Code that changes the value in a way the predicate may care about must call `notify_all` afterwards.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let ready = WithLock::new_shared(false);
+	/// let producer = std::sync::Arc::clone(&ready);
+	/// let handle = std::thread::spawn(move || {
+	///     producer.with_lock(|s| *s = true);
+	///     producer.notify_all();
+	/// });
+	/// ready.wait_until(|s| *s);
+	/// handle.join().unwrap();
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn wait_until<F>(&self, mut predicate: F)
+	where
+		F: FnMut(&mut T) -> bool,
+	{
+		let mut lock = self.acquire();
+		while !predicate(&mut *lock) {
+			lock.wait(&self.condvar);
+		}
 	}
 
-	#[test]
-	fn test_mutex_cell_into_inner() {
-		let c = MutexCell::new(5);
-		let five = c.into_inner();
+	/// Wakes up every thread blocked in [`wait_until`](WithLock::wait_until).
+	#[cfg(feature = "std")]
+	pub fn notify_all(&self) {
+		self.condvar.notify_all();
+	}
 
-		assert_eq!(five, 5);
+	/// Returns how many times a caller had to wait because the lock was already held.
+	///
+	/// Only blocking acquisitions are counted, including timed ones like [`with_lock_timeout`](WithLock::with_lock_timeout) (even if they time out); `try_*` methods that give up are not.
+	#[cfg(feature = "metrics")]
+	#[must_use]
+	pub fn contention_count(&self) -> u64 {
+		self.contention.load(Ordering::Relaxed)
+	}
+
+	/// Returns how many times the lock has been held, and the total time it was held for.
+	///
+	/// Every acquisition is counted once it is released, whichever method took it.
+	/// While [`wait_until`](WithLock::wait_until) is parked the lock is released, so that time isn't counted, and each wake-up counts as a new hold.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::<i64>::new(1);
+	/// lock.with_lock(|s| *s += 1);
+	/// let (count, held) = lock.hold_stats();
+	/// assert_eq!(count, 1);
+	/// println!("held for {:?} in total", held);
+	/// ```
+	#[cfg(feature = "metrics")]
+	#[must_use]
+	pub fn hold_stats(&self) -> (u64, Duration) {
+		(
+			self.hold.count.load(Ordering::Relaxed),
+			Duration::from_nanos(self.hold.nanos.load(Ordering::Relaxed)),
+		)
+	}
+
+	/// Like [`with_lock`](WithLock::with_lock), but also returns how long it waited to acquire the lock.
+	///
+	/// Only the wait is measured, not the time spent running the function.
+	#[cfg(feature = "std")]
+	pub fn with_lock_timed<F, U>(&self, function: F) -> (U, Duration)
+	where
+		F: FnOnce(&mut T) -> U,
+	{
+		let start = Instant::now();
+		let mut lock = self.acquire();
+		let waited = start.elapsed();
+		(function(&mut *lock), waited)
+	}
+
+	/// Like [`with_lock`](WithLock::with_lock), but logs a warning if the function held the lock for longer than `threshold`.
+	///
+	/// Long critical sections are the most common precursor to deadlocks and contention, so this helps find them during development.
+	#[cfg(feature = "log")]
+	pub fn with_lock_warn<F, U>(&self, threshold: Duration, function: F) -> U
+	where
+		F: FnOnce(&mut T) -> U,
+	{
+		self.with_lock_warn_with(threshold, function, |held| {
+			log::warn!(
+				"WithLock<{}> was held for {:?}, longer than the {:?} threshold",
+				core::any::type_name::<T>(),
+				held,
+				threshold
+			)
+		})
+	}
+
+	/// Like [`with_lock`](WithLock::with_lock), but calls `on_slow` with the elapsed time if the function held the lock for longer than `threshold`.
+	///
+	/// `on_slow` runs after the lock has been released.
+	/// ## Examples
+	/// ```rust
+	/// use std::time::Duration;
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::<i64>::new(1);
+	/// lock.with_lock_warn_with(
+	///     Duration::from_millis(100),
+	///     |s| *s += 1,
+	///     |held| eprintln!("lock held for {:?}", held),
+	/// );
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn with_lock_warn_with<F, U, W>(&self, threshold: Duration, function: F, on_slow: W) -> U
+	where
+		F: FnOnce(&mut T) -> U,
+		W: FnOnce(Duration),
+	{
+		let (result, held) = self.with_lock(|s| {
+			let start = Instant::now();
+			let result = function(s);
+			(result, start.elapsed())
+		});
+		if held > threshold {
+			on_slow(held);
+		}
+		result
+	}
+
+	/// Like [`with_lock`](WithLock::with_lock), but with poisoning similar to [`std::sync::Mutex`].
+	///
+	/// If the function panics, the panic is caught, the lock is marked as poisoned and `Err(LockPoisoned)` is returned.
+	/// While poisoned, every call returns `Err(LockPoisoned)` without running the function, until [`clear_poison`](WithLock::clear_poison) is called.
+	/// Panics in plain `with_lock` calls do not poison the lock.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithLock;
+	/// let lock = WithLock::<i64>::new(1);
+	/// assert!(lock.with_lock_checked(|_| panic!("oops")).is_err());
+	/// assert!(lock.is_poisoned());
+	/// lock.clear_poison();
+	/// assert_eq!(lock.with_lock_checked(|s| *s), Ok(1));
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn with_lock_checked<F, U>(&self, function: F) -> Result<U, LockPoisoned>
+	where
+		F: FnOnce(&mut T) -> U,
+	{
+		let mut lock = self.acquire();
+		if self.is_poisoned() {
+			return Err(LockPoisoned);
+		}
+		panic::catch_unwind(AssertUnwindSafe(|| function(&mut *lock))).map_err(|_| {
+			self.poisoned.store(true, Ordering::Relaxed);
+			LockPoisoned
+		})
+	}
+
+	/// Returns whether a [`with_lock_checked`](WithLock::with_lock_checked) call has panicked since the poison was last cleared.
+	#[cfg(feature = "std")]
+	#[must_use]
+	pub fn is_poisoned(&self) -> bool {
+		self.poisoned.load(Ordering::Relaxed)
+	}
+
+	/// Clears the poisoned state, allowing [`with_lock_checked`](WithLock::with_lock_checked) to run again.
+	#[cfg(feature = "std")]
+	pub fn clear_poison(&self) {
+		self.poisoned.store(false, Ordering::Relaxed);
+	}
+}
+
+/// The error returned by [`WithLock::with_lock_checked`] when the lock is poisoned.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockPoisoned;
+
+#[cfg(feature = "std")]
+impl fmt::Display for LockPoisoned {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("a function panicked while holding this lock")
+	}
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for LockPoisoned {}
+
+impl<T: fmt::Debug> WithLock<T> {
+	/// Formats as a struct named `name`, without blocking if the lock is held.
+	fn fmt_as(&self, name: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		struct LockedPlaceholder;
+
+		impl fmt::Debug for LockedPlaceholder {
+			fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+				f.write_str("<locked>")
+			}
+		}
+
+		let mut d = f.debug_struct(name);
+		match self.try_acquire() {
+			Some(guard) => d.field("data", &&*guard),
+			None => d.field("data", &LockedPlaceholder),
+		};
+		d.finish()
+	}
+}
+
+impl<T: Default> Default for WithLock<T> {
+	fn default() -> WithLock<T> {
+		WithLock::<T>::new(T::default())
+	}
+}
+
+impl<T> From<T> for WithLock<T> {
+	fn from(data: T) -> WithLock<T> {
+		WithLock::<T>::new(data)
+	}
+}
+
+impl<T: fmt::Debug> fmt::Debug for WithLock<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.fmt_as("WithLock", f)
+	}
+}
+
+/// Locks two `WithLock`s at once and runs the function with both values.
+///
+/// The locks are always acquired in the same order (by address), so two threads calling
+/// `lock2(&x, &y, ..)` and `lock2(&y, &x, ..)` cannot deadlock each other.
+///
+/// # Panics
+/// Panics if `a` and `b` are the same lock.
+/// ## Examples
+/// ```rust
+/// use with_lock::{lock2, WithLock};
+/// let a = WithLock::<i64>::new(2);
+/// let b = WithLock::<i64>::new(3);
+/// lock2(&a, &b, |a, b| std::mem::swap(a, b));
+/// assert_eq!(a.with_lock(|s| *s), 3);
+/// ```
+pub fn lock2<A, B, F, R>(a: &WithLock<A>, b: &WithLock<B>, function: F) -> R
+where
+	F: FnOnce(&mut A, &mut B) -> R,
+{
+	let a_addr = a as *const WithLock<A> as usize;
+	let b_addr = b as *const WithLock<B> as usize;
+	assert_ne!(a_addr, b_addr, "lock2 called with the same lock twice");
+	let (mut a_lock, mut b_lock);
+	if a_addr < b_addr {
+		a_lock = a.acquire();
+		b_lock = b.acquire();
+	} else {
+		b_lock = b.acquire();
+		a_lock = a.acquire();
+	}
+	function(&mut *a_lock, &mut *b_lock)
+}
+
+/// Locks any number of `WithLock`s at once and runs the function with all their values.
+///
+/// The values are handed over in the same order as `locks`, but the locks are always acquired in the same order (by address),
+/// so threads locking overlapping sets in different orders cannot deadlock each other.
+///
+/// # Panics
+/// Panics if the same lock appears more than once.
+/// ## Examples
+/// ```rust
+/// use with_lock::{lock_many, WithLock};
+/// let (a, b, c) = (WithLock::new(1), WithLock::new(2), WithLock::new(3));
+/// let sum = lock_many(&[&c, &a, &b], |values| {
+///     *values[0] = 0;
+///     values.iter().map(|v| **v).sum::<i32>()
+/// });
+/// assert_eq!(sum, 3);
+/// assert_eq!(c.with_lock(|s| *s), 0);
+/// ```
+#[cfg(feature = "std")]
+pub fn lock_many<T, R, F>(locks: &[&WithLock<T>], function: F) -> R
+where
+	F: FnOnce(&mut [&mut T]) -> R,
+{
+	let addr = |i: usize| locks[i] as *const WithLock<T> as usize;
+	let mut order: Vec<usize> = (0..locks.len()).collect();
+	order.sort_unstable_by_key(|&i| addr(i));
+	for pair in order.windows(2) {
+		assert_ne!(
+			addr(pair[0]),
+			addr(pair[1]),
+			"lock_many called with the same lock twice"
+		);
+	}
+	let mut guards: Vec<Option<Held<'_, T>>> = locks.iter().map(|_| None).collect();
+	for i in order {
+		guards[i] = Some(locks[i].acquire());
+	}
+	let mut values: Vec<&mut T> = guards
+		.iter_mut()
+		.map(|guard| &mut **guard.as_mut().expect("every lock is held"))
+		.collect();
+	function(&mut values)
+}
+
+/// Swaps each cell in `a` with the cell at the same index in `b`.
+///
+/// Each pair is locked in a consistent order, like [`lock2`], so this cannot deadlock with other swaps of the same cells.
+/// Only one pair is locked at a time: every pair is swapped atomically, but the slices as a whole are not.
+/// A cell paired with itself is left alone.
+///
+/// # Panics
+/// Panics if the slices have different lengths.
+/// ## Examples
+/// ```rust
+/// use with_lock::{swap_slices, MutexCell};
+/// let front = [MutexCell::new(1), MutexCell::new(2)];
+/// let back = [MutexCell::new(3), MutexCell::new(4)];
+/// swap_slices(&front, &back);
+/// assert_eq!(front[0].get(), 3);
+/// assert_eq!(back[1].get(), 2);
+/// ```
+pub fn swap_slices<T>(a: &[MutexCell<T>], b: &[MutexCell<T>]) {
+	assert_eq!(
+		a.len(),
+		b.len(),
+		"swap_slices called with slices of different lengths"
+	);
+	for (a, b) in a.iter().zip(b) {
+		a.swap(b);
+	}
+}
+
+/// Folds the values of several cells into one, locking one cell at a time.
+///
+/// Each cell is only locked while `function` runs on its value, so this never holds more than one lock.
+/// That also means the result is not an atomic snapshot: other threads may change cells that were already visited, or not visited yet.
+/// ## Examples
+/// ```rust
+/// use with_lock::{fold_cells, MutexCell};
+/// let cells = vec![MutexCell::new(1), MutexCell::new(2), MutexCell::new(3)];
+/// assert_eq!(fold_cells(&cells, 0, |sum, value| sum + value), 6);
+/// ```
+pub fn fold_cells<T, A, F>(cells: &[MutexCell<T>], init: A, mut function: F) -> A
+where
+	F: FnMut(A, &T) -> A,
+{
+	cells
+		.iter()
+		.fold(init, |acc, cell| cell.map(|value| function(acc, value)))
+}
+
+/// Starts a background thread that checks for deadlocks every second and reports them.
+///
+/// Each deadlock is logged as an error with the `log` feature, or printed to stderr otherwise,
+/// listing the threads involved and their backtraces. Use [`spawn_deadlock_checker_with`] to handle them yourself.
+/// The thread runs for the rest of the program.
+///
+/// Only `parking_lot` locks are tracked, which covers everything in this crate except [`AsyncWithLock`](struct.AsyncWithLock.html).
+#[cfg(feature = "deadlock-detection")]
+pub fn spawn_deadlock_checker() -> std::thread::JoinHandle<()> {
+	spawn_deadlock_checker_with(Duration::from_secs(1), |deadlocks| {
+		for (i, threads) in deadlocks.iter().enumerate() {
+			for thread in threads {
+				#[cfg(feature = "log")]
+				log::error!(
+					"deadlock #{}: thread {:?}\n{}",
+					i,
+					thread.thread_id,
+					thread.backtrace
+				);
+				#[cfg(not(feature = "log"))]
+				eprintln!(
+					"deadlock #{}: thread {:?}\n{}",
+					i, thread.thread_id, thread.backtrace
+				);
+			}
+		}
+	})
+}
+
+/// Like [`spawn_deadlock_checker`], but checks every `interval` and passes each batch of deadlocks found to `on_deadlock`.
+///
+/// Every deadlock is a cycle of threads waiting on each other, and is only reported once.
+/// ## Examples
+/// ```rust
+/// use std::time::Duration;
+/// with_lock::spawn_deadlock_checker_with(Duration::from_millis(100), |deadlocks| {
+///     eprintln!("{} deadlocks found", deadlocks.len());
+/// });
+/// ```
+#[cfg(feature = "deadlock-detection")]
+pub fn spawn_deadlock_checker_with<F>(
+	interval: Duration,
+	mut on_deadlock: F,
+) -> std::thread::JoinHandle<()>
+where
+	F: FnMut(Vec<Vec<DeadlockedThread>>) + Send + 'static,
+{
+	std::thread::spawn(move || loop {
+		std::thread::sleep(interval);
+		let deadlocks = parking_lot::deadlock::check_deadlock();
+		if !deadlocks.is_empty() {
+			on_deadlock(
+				deadlocks
+					.iter()
+					.map(|threads| {
+						threads
+							.iter()
+							.map(|thread| DeadlockedThread {
+								thread_id: thread.thread_id(),
+								backtrace: format!("{:?}", thread.backtrace()),
+							})
+							.collect()
+					})
+					.collect(),
+			);
+		}
+	})
+}
+
+/// A thread caught in a deadlock, as reported by [`spawn_deadlock_checker_with`].
+#[cfg(feature = "deadlock-detection")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadlockedThread {
+	/// The id of the deadlocked thread.
+	pub thread_id: std::thread::ThreadId,
+	/// A formatted backtrace of where the thread is blocked.
+	pub backtrace: String,
+}
+
+/// A field-scoped accessor returned by [`WithLock::project`].
+pub struct Projection<'a, T, F> {
+	pub(crate) lock: &'a WithLock<T>,
+	pub(crate) selector: F,
+}
+
+impl<T, F> Projection<'_, T, F> {
+	/// Locks the parent `WithLock` and runs the function with the projected part.
+	pub fn with_lock<U, G, R>(&self, function: G) -> R
+	where
+		F: Fn(&mut T) -> &mut U,
+		G: FnOnce(&mut U) -> R,
+	{
+		self.lock.with_lock(|s| function((self.selector)(s)))
+	}
+}
+
+/// A guard returned by [`WithLock::lock`]. The lock is released when it is dropped.
+#[must_use = "if unused the lock will immediately unlock"]
+pub struct WithLockGuard<'a, T> {
+	pub(crate) guard: Held<'a, T>,
+}
+
+impl<T> Deref for WithLockGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		&self.guard
+	}
+}
+
+impl<T> DerefMut for WithLockGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		&mut self.guard
+	}
+}
+
+/// A guard returned by [`WithLock::lock_owned`]. It owns a clone of the [`Arc`], and releases the lock when dropped.
+#[cfg(feature = "std")]
+#[must_use = "if unused the lock will immediately unlock"]
+pub struct ArcWithLockGuard<T: 'static> {
+	// Declared first, so the lock is released before the `Arc` is dropped.
+	guard: Held<'static, T>,
+	lock: Arc<WithLock<T>>,
+}
+
+#[cfg(feature = "std")]
+impl<T> ArcWithLockGuard<T> {
+	/// Returns the `Arc` this guard is holding the lock of.
+	pub fn lock(guard: &Self) -> &Arc<WithLock<T>> {
+		&guard.lock
+	}
+}
+
+#[cfg(feature = "std")]
+impl<T> Deref for ArcWithLockGuard<T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		&self.guard
+	}
+}
+
+#[cfg(feature = "std")]
+impl<T> DerefMut for ArcWithLockGuard<T> {
+	fn deref_mut(&mut self) -> &mut T {
+		&mut self.guard
+	}
+}
+
+/// A locked [`WithLock`]. With the `metrics` feature, dropping it records how long the lock was held.
+pub(crate) struct Held<'a, T> {
+	// Declared first, so the lock is released before the hold time is recorded.
+	pub(crate) guard: MutexGuard<'a, T>,
+	#[cfg(feature = "metrics")]
+	timer: HoldTimer<'a>,
+}
+
+#[cfg(feature = "std")]
+impl<T> Held<'_, T> {
+	/// Releases the lock until `condvar` is notified, then takes it again.
+	///
+	/// With the `metrics` feature, the time before waiting is recorded as one hold and the timer restarts once the lock is back,
+	/// so the time spent parked isn't counted as held.
+	fn wait(&mut self, condvar: &Condvar) {
+		#[cfg(feature = "metrics")]
+		self.timer.record();
+		condvar.wait(&mut self.guard);
+		#[cfg(feature = "metrics")]
+		{
+			self.timer.since = Instant::now();
+		}
+	}
+}
+
+impl<T> Deref for Held<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		&self.guard
+	}
+}
+
+impl<T> DerefMut for Held<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		&mut self.guard
+	}
+}
+
+/// How often a [`WithLock`] was held, and for how long in total.
+#[cfg(feature = "metrics")]
+pub(crate) struct HoldStats {
+	count: AtomicU64,
+	nanos: AtomicU64,
+}
+
+#[cfg(feature = "metrics")]
+struct HoldTimer<'a> {
+	stats: &'a HoldStats,
+	since: Instant,
+}
+
+#[cfg(feature = "metrics")]
+impl HoldTimer<'_> {
+	/// Adds one hold, lasting from `since` until now, to the stats.
+	fn record(&self) {
+		let nanos = self.since.elapsed().as_nanos().min(u64::MAX as u128) as u64;
+		self.stats.count.fetch_add(1, Ordering::Relaxed);
+		self.stats.nanos.fetch_add(nanos, Ordering::Relaxed);
+	}
+}
+
+#[cfg(feature = "metrics")]
+impl Drop for HoldTimer<'_> {
+	fn drop(&mut self) {
+		self.record();
+	}
+}
+
+/// A read-write lock counterpart to [`WithLock`], for data that is read far more often than it is written.
+///
+/// Any number of readers may hold the lock at once, while a writer has exclusive access.
+pub struct WithRwLock<T> {
+	pub(crate) data: RwLock<T>,
+}
+
+impl<T> WithRwLock<T> {
+	const_fn! {
+		/// Create a new `WithRwLock` instance.
+		/// ## Examples
+		/// ```rust
+		/// use with_lock::WithRwLock;
+		/// let lock = WithRwLock::new(123);
+		/// assert_eq!(lock.read_with(|s| *s), 123);
+		/// ```
+		pub const fn new(data: T) -> WithRwLock<T> {
+			WithRwLock {
+				data: const_rwlock(data),
+			}
+		}
+	}
+
+	/// Runs the function with shared read access, releasing the lock afterwards.
+	pub fn read_with<F, U>(&self, function: F) -> U
+	where
+		F: FnOnce(&T) -> U,
+	{
+		let lock = self.data.read();
+		function(&*lock)
+	}
+
+	/// Runs the function with exclusive write access, releasing the lock afterwards.
+	pub fn write_with<F, U>(&self, function: F) -> U
+	where
+		F: FnOnce(&mut T) -> U,
+	{
+		let mut lock = self.data.write();
+		function(&mut *lock)
+	}
+
+	/// Runs the function with an upgradable read lock, releasing it afterwards.
+	///
+	/// An upgradable read can run alongside plain readers, but excludes writers and other upgradable readers.
+	/// The function can call [`UpgradableRead::upgrade`] to atomically turn it into a write lock, so nothing can change between reading and writing.
+	/// ## Examples
+	/// ```rust
+	/// use with_lock::WithRwLock;
+	/// let lock = WithRwLock::new(1);
+	/// lock.upgradable_read_with(|s| {
+	///     if **s < 10 {
+	///         *s.upgrade() = 10;
+	///     }
+	/// });
+	/// assert_eq!(lock.read_with(|s| *s), 10);
+	/// ```
+	pub fn upgradable_read_with<F, U>(&self, function: F) -> U
+	where
+		F: FnOnce(&mut UpgradableRead<'_, T>) -> U,
+	{
+		let mut lock = UpgradableRead {
+			state: UpgradableState::Read(sys::upgradable_read(&self.data)),
+		};
+		function(&mut lock)
+	}
+}
+
+/// An upgradable read lock, handed out by [`WithRwLock::upgradable_read_with`].
+///
+/// It dereferences to the value, and can be upgraded to a write lock with [`upgrade`](UpgradableRead::upgrade).
+pub struct UpgradableRead<'a, T> {
+	pub(crate) state: UpgradableState<'a, T>,
+}
+
+pub(crate) enum UpgradableState<'a, T> {
+	Read(RwLockUpgradableReadGuard<'a, T>),
+	Write(RwLockWriteGuard<'a, T>),
+	Upgrading,
+}
+
+impl<T> UpgradableRead<'_, T> {
+	/// Upgrades to a write lock, waiting for any readers to finish, and returns a mutable reference to the value.
+	///
+	/// Calling this again once upgraded just returns the reference.
+	pub fn upgrade(&mut self) -> &mut T {
+		if let UpgradableState::Read(_) = self.state {
+			if let UpgradableState::Read(lock) =
+				mem::replace(&mut self.state, UpgradableState::Upgrading)
+			{
+				self.state = UpgradableState::Write(sys::upgrade(lock));
+			}
+		}
+		match &mut self.state {
+			UpgradableState::Write(lock) => &mut *lock,
+			_ => unreachable!(),
+		}
+	}
+
+	/// Returns whether the lock has been upgraded to a write lock.
+	#[must_use]
+	pub fn is_upgraded(&self) -> bool {
+		matches!(self.state, UpgradableState::Write(_))
+	}
+}
+
+impl<T> Deref for UpgradableRead<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		match &self.state {
+			UpgradableState::Read(lock) => lock,
+			UpgradableState::Write(lock) => lock,
+			UpgradableState::Upgrading => unreachable!(),
+		}
+	}
+}
+
+/// A variant of [`WithLock`] that can be locked again by the thread already holding it.
+///
+/// Because several calls on the same thread may be active at once, functions only receive a shared reference.
+/// Use a type with interior mutability, such as a [`RefCell`](std::cell::RefCell), to mutate the value.
+/// ## Examples
+/// ```rust
+/// use std::cell::RefCell;
+/// use with_lock::ReentrantWithLock;
+/// let lock = ReentrantWithLock::new(RefCell::new(1));
+/// lock.with_lock(|outer| {
+///     lock.with_lock(|inner| *inner.borrow_mut() += 1);
+///     assert_eq!(*outer.borrow(), 2);
+/// });
+/// ```
+#[cfg(feature = "std")]
+pub struct ReentrantWithLock<T> {
+	pub(crate) data: ReentrantMutex<T>,
+}
+
+#[cfg(feature = "std")]
+impl<T> ReentrantWithLock<T> {
+	const_fn! {
+		/// Create a new `ReentrantWithLock` instance.
+		pub const fn new(data: T) -> ReentrantWithLock<T> {
+			ReentrantWithLock {
+				data: const_reentrant_mutex(data),
+			}
+		}
+	}
+
+	/// Runs the function with the lock held, releasing it afterwards.
+	///
+	/// Calling this again from inside the function, on the same thread, does not deadlock.
+	pub fn with_lock<F, U>(&self, function: F) -> U
+	where
+		F: FnOnce(&T) -> U,
+	{
+		let lock = self.data.lock();
+		function(&*lock)
+	}
+}
+
+/// An async counterpart to [`WithLock`], backed by `tokio::sync::Mutex`.
+///
+/// Waiting for the lock yields to the executor instead of blocking the thread.
+/// The function itself is synchronous, so the lock can never be held across an `.await`.
+#[cfg(feature = "async")]
+pub struct AsyncWithLock<T> {
+	pub(crate) data: tokio::sync::Mutex<T>,
+}
+
+#[cfg(feature = "async")]
+impl<T> AsyncWithLock<T> {
+	/// Create a new `AsyncWithLock` instance.
+	pub fn new(data: T) -> AsyncWithLock<T> {
+		AsyncWithLock {
+			data: tokio::sync::Mutex::new(data),
+		}
+	}
+
+	/// Waits for the lock, then runs the function with it held and releases it.
+	/// ## Examples
+	/// ```rust
+	/// # async fn run() {
+	/// use with_lock::AsyncWithLock;
+	/// let lock = AsyncWithLock::new(1);
+	/// lock.with_lock_async(|s| *s += 1).await;
+	/// assert_eq!(lock.with_lock_async(|s| *s).await, 2);
+	/// # }
+	/// ```
+	pub async fn with_lock_async<F, U>(&self, function: F) -> U
+	where
+		F: FnOnce(&mut T) -> U,
+	{
+		let mut lock = self.data.lock().await;
+		function(&mut *lock)
+	}
+}
+
+// Not every target has 64-bit atomics; those count in a `usize` instead, which can wrap.
+#[cfg(target_has_atomic = "64")]
+type GenerationCounter = AtomicU64;
+#[cfg(not(target_has_atomic = "64"))]
+type GenerationCounter = AtomicUsize;
+
+pub struct MutexCell<T> {
+	pub(crate) data: WithLock<T>,
+	pub(crate) generation: GenerationCounter,
+	#[cfg(feature = "std")]
+	pub(crate) observers: ObserverSlot<T>,
+}
+
+/// Where a [`MutexCell`] keeps its [`on_change`](MutexCell::on_change) callbacks.
+///
+/// It is a single pointer, null until the first callback is registered, so cells without callbacks only pay for the pointer.
+#[cfg(feature = "std")]
+pub(crate) struct ObserverSlot<T> {
+	ptr: AtomicPtr<Observers<T>>,
+	_owns: PhantomData<Box<Observers<T>>>,
+}
+
+/// The callbacks registered with [`MutexCell::on_change`].
+#[cfg(feature = "std")]
+pub(crate) struct Observers<T> {
+	// Captured by `on_change`, which is the only place that knows `T: Clone`.
+	clone: fn(&T) -> T,
+	callbacks: Mutex<Vec<Callback<T>>>,
+}
+
+#[cfg(feature = "std")]
+impl<T> ObserverSlot<T> {
+	const_fn! {
+		const fn new() -> ObserverSlot<T> {
+			ObserverSlot {
+				ptr: AtomicPtr::new(ptr::null_mut()),
+				_owns: PhantomData,
+			}
+		}
+	}
+
+	/// Returns the registry, if a callback has ever been registered.
+	fn get(&self) -> Option<&Observers<T>> {
+		// SAFETY: a non-null pointer comes from `Box::into_raw` in `get_or_init`, and is only freed when the slot is dropped.
+		unsafe { self.ptr.load(Ordering::Acquire).as_ref() }
+	}
+
+	/// Returns the registry, allocating it first if needed.
+	fn get_or_init(&self, clone: fn(&T) -> T) -> &Observers<T> {
+		if let Some(observers) = self.get() {
+			return observers;
+		}
+		let new = Box::into_raw(Box::new(Observers {
+			clone,
+			callbacks: const_mutex(Vec::new()),
+		}));
+		match self
+			.ptr
+			.compare_exchange(ptr::null_mut(), new, Ordering::AcqRel, Ordering::Acquire)
+		{
+			// SAFETY: `new` is now owned by the slot, which keeps it alive for as long as `self`.
+			Ok(_) => unsafe { &*new },
+			Err(existing) => {
+				// SAFETY: another thread won the race, so `new` was never shared and is still ours to free.
+				drop(unsafe { Box::from_raw(new) });
+				// SAFETY: as in `get`.
+				unsafe { &*existing }
+			}
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl<T> Drop for ObserverSlot<T> {
+	fn drop(&mut self) {
+		let observers = self.ptr.load(Ordering::Acquire);
+		if !observers.is_null() {
+			// SAFETY: the pointer came from `Box::into_raw`, and `&mut self` means nothing can still be using it.
+			drop(unsafe { Box::from_raw(observers) });
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+type Callback<T> = Arc<dyn Fn(&T) + Send + Sync>;
+
+impl<T> MutexCell<T> {
+	const_fn! {
+		/// Create a new MutexCell with a value.
+		///
+		/// This is a `const fn`, so it can be used to initialize a `static`.
+		/// ## Example
+		/// ```rust
+		/// use with_lock::MutexCell;
+		/// let mutex = MutexCell::new(23);
+		/// assert_eq!(mutex.get(), 23);
+		///
+		/// static COUNTER: MutexCell<u64> = MutexCell::new(0);
+		/// COUNTER.update(|c| *c += 1);
+		/// assert_eq!(COUNTER.get(), 1)
+		/// ```
+		pub const fn new(data: T) -> MutexCell<T> {
+			MutexCell {
+				data: WithLock::<T>::new(data),
+				generation: GenerationCounter::new(0),
+				#[cfg(feature = "std")]
+				observers: ObserverSlot::new(),
+			}
+		}
+	}
+
+	/// Registers a callback that is called with the new value whenever [`set`](MutexCell::set),
+	/// [`replace`](MutexCell::replace) (and so [`take`](MutexCell::take)) or [`update`](MutexCell::update) change the cell,
+	/// and for a `MutexCell<bool>` whenever it is toggled or set with [`set_true`](MutexCell::set_true) or [`set_false`](MutexCell::set_false).
+	///
+	/// Callbacks run on the thread that made the change, in registration order, after the cell has been unlocked.
+	/// They get a clone of the new value, so they may lock this cell again, change it (which calls them again), or register more callbacks.
+	/// Since the cell is already unlocked, callbacks for changes made at the same time on different threads may run in either order:
+	/// the last value a callback was given is not necessarily the stored one. Read the cell again, or compare [`generation`](MutexCell::generation)s, when that matters.
+	/// Cells without callbacks don't pay for them beyond one pointer: the registry is only allocated, and a change only locks it, once a callback has been registered.
+	/// Other ways of changing the value, like [`get_mut`](MutexCell::get_mut) or [`scope`](MutexCell::scope), don't call them.
+	/// Clones of the cell start without callbacks.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(1);
+	/// cell.on_change(Box::new(|v| println!("now {}", v)));
+	/// cell.set(2);
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn on_change(&self, callback: Box<dyn Fn(&T) + Send + Sync>)
+	where
+		T: Clone,
+	{
+		self.observers
+			.get_or_init(T::clone)
+			.callbacks
+			.lock()
+			.push(Arc::from(callback));
+	}
+
+	/// Runs the function under the lock, then calls the [`on_change`](MutexCell::on_change) callbacks with the new value.
+	fn change<F, U>(&self, function: F) -> U
+	where
+		F: FnOnce(&mut T) -> U,
+	{
+		#[cfg(feature = "std")]
+		{
+			let observers = match self.observers.get() {
+				Some(observers) => observers,
+				None => return self.write(function),
+			};
+			let (result, value, callbacks) = self.write(|s| {
+				let result = function(s);
+				(
+					result,
+					(observers.clone)(s),
+					observers.callbacks.lock().clone(),
+				)
+			});
+			for callback in callbacks {
+				callback(&value);
+			}
+			result
+		}
+		#[cfg(not(feature = "std"))]
+		self.write(function)
+	}
+
+	/// Runs the function under the lock, advancing the [`generation`](MutexCell::generation).
+	fn write<F, U>(&self, function: F) -> U
+	where
+		F: FnOnce(&mut T) -> U,
+	{
+		self.data.with_lock(|s| {
+			self.generation.fetch_add(1, Ordering::Release);
+			function(s)
+		})
+	}
+
+	/// Returns a counter that advances every time the value may have changed.
+	///
+	/// Every method that can change the value advances it, under the same lock as the change,
+	/// so a value read together with its generation can later be checked for staleness without reading the value again.
+	/// It may also advance when a call turns out not to change anything, like a [`compare_and_swap`](MutexCell::compare_and_swap) that doesn't match.
+	/// Changes made through [`as_ptr`](MutexCell::as_ptr) are not tracked.
+	///
+	/// On targets without 64-bit atomics the counter is only as wide as a `usize`, so on 32-bit targets it wraps after 2³² changes.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(1);
+	/// let seen = cell.generation();
+	/// assert_eq!(cell.get(), 1);
+	/// assert_eq!(cell.generation(), seen);
+	/// cell.set(2);
+	/// assert_ne!(cell.generation(), seen)
+	/// ```
+	#[must_use]
+	pub fn generation(&self) -> u64 {
+		// A no-op cast where the counter is already an `AtomicU64`.
+		#[allow(clippy::unnecessary_cast)]
+		let generation = self.generation.load(Ordering::Acquire) as u64;
+		generation
+	}
+
+	/// Returns a copy of the value and its [`generation`](MutexCell::generation), or `None` if the generation is still `last_seen`.
+	///
+	/// The value and generation are read under one lock, so they always belong together.
+	/// Where the [`generation`](MutexCell::generation) can wrap, a `last_seen` that is exactly a whole wrap behind reads as unchanged.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(1);
+	/// let (value, seen) = cell.get_if_changed(u64::MAX).unwrap();
+	/// assert_eq!(value, 1);
+	/// assert_eq!(cell.get_if_changed(seen), None);
+	/// cell.set(2);
+	/// assert_eq!(cell.get_if_changed(seen).map(|(v, _)| v), Some(2))
+	/// ```
+	#[must_use]
+	pub fn get_if_changed(&self, last_seen: u64) -> Option<(T, u64)>
+	where
+		T: Copy,
+	{
+		self.data.with_lock(|s| {
+			let generation = self.generation();
+			if generation == last_seen {
+				None
+			} else {
+				Some((*s, generation))
+			}
+		})
+	}
+
+	/// Create a new MutexCell with the value returned by `f`.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new_with(|| vec![0u8; 4]);
+	/// assert_eq!(cell.map(|v| v.len()), 4)
+	/// ```
+	pub fn new_with<F>(f: F) -> MutexCell<T>
+	where
+		F: FnOnce() -> T,
+	{
+		MutexCell::new(f())
+	}
+
+	/// Creates an array of `N` independent `MutexCell`s, initializing each with `f` called on its index.
+	///
+	/// `[MutexCell::new(x); N]` doesn't compile, since `MutexCell` isn't `Copy`; this fills the gap.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cells: [MutexCell<usize>; 3] = MutexCell::array(|i| i * 10);
+	/// assert_eq!(cells[2].get(), 20)
+	/// ```
+	pub fn array<const N: usize>(f: impl Fn(usize) -> T) -> [MutexCell<T>; N] {
+		core::array::from_fn(|i| MutexCell::new(f(i)))
+	}
+
+	/// Create a new `MutexCell` wrapped in an [`Arc`], ready to be shared between threads.
+	#[cfg(feature = "std")]
+	pub fn new_shared(data: T) -> Arc<MutexCell<T>> {
+		Arc::new(MutexCell::new(data))
+	}
+
+	/// Moves this `MutexCell` into an [`Arc`], so an existing cell can be shared between threads.
+	#[cfg(feature = "std")]
+	pub fn into_arc(self) -> Arc<MutexCell<T>> {
+		Arc::new(self)
+	}
+
+	/// Returns a copy of the contained value.
+	///
+	/// None of the backends poison their locks: a panic while the cell is locked leaves it usable (see the `parking-lot` feature),
+	/// so this always returns the stored value, even after a panic on another thread.
+	#[must_use]
+	pub fn get(&self) -> T
+	where
+		T: Copy,
+	{
+		self.data.with_lock(|s| *s)
+	}
+
+	/// Returns a clone of the contained value.
+	///
+	/// This is the counterpart to [`get`](MutexCell::get) for types that are `Clone` but not `Copy`, like `String`.
+	/// It can always read the value, for the same reason as `get`.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(String::from("hello"));
+	/// assert_eq!(cell.clone_inner(), "hello")
+	/// ```
+	#[must_use]
+	pub fn clone_inner(&self) -> T
+	where
+		T: Clone,
+	{
+		self.data.with_lock(|s| s.clone())
+	}
+
+	/// Returns a copy of the contained value, or `None` if the cell is currently locked.
+	///
+	/// Unlike [`get`](MutexCell::get), this never blocks.
+	#[must_use]
+	pub fn try_get(&self) -> Option<T>
+	where
+		T: Copy,
+	{
+		self.data.try_with_lock(|s| *s)
+	}
+
+	/// Returns whether the cell is currently locked.
+	///
+	/// See [`WithLock::is_locked`] for caveats.
+	#[must_use]
+	pub fn is_locked(&self) -> bool {
+		self.data.is_locked()
+	}
+
+	/// Returns a mutable reference to the underlying data.
+	///
+	/// No locking is needed, since the mutable borrow guarantees there are no other users.
+	pub fn get_mut(&mut self) -> &mut T {
+		self.generation.fetch_add(1, Ordering::Relaxed);
+		self.data.get_mut()
+	}
+
+	/// Returns a raw pointer to the contained value, like [`Cell::as_ptr`](core::cell::Cell::as_ptr).
+	///
+	/// Getting the pointer does not lock the cell, and using it bypasses the lock entirely.
+	/// Dereferencing it is only sound while you otherwise guarantee exclusive access, for example while holding the lock through [`update`](MutexCell::update) or [`WithLock::lock`].
+	/// Reading or writing through it while another thread uses the cell is a data race, and undefined behavior.
+	pub fn as_ptr(&self) -> *mut T {
+		sys::data_ptr(&self.data.data)
+	}
+
+	/// Sets the contained value.
+	///
+	/// To get the previous value back in the same lock acquisition, use [`replace`](MutexCell::replace).
+	pub fn set(&self, data: T) {
+		self.change(|s| *s = data)
+	}
+
+	/// Returns a copy of the contained value. The same as [`get`](MutexCell::get), named after [`AtomicUsize::load`](core::sync::atomic::AtomicUsize::load).
+	#[doc(alias = "get")]
+	#[must_use]
+	pub fn load(&self) -> T
+	where
+		T: Copy,
+	{
+		self.get()
+	}
+
+	/// Sets the contained value. The same as [`set`](MutexCell::set), named after [`AtomicUsize::store`](core::sync::atomic::AtomicUsize::store).
+	#[doc(alias = "set")]
+	pub fn store(&self, val: T) {
+		self.set(val)
+	}
+
+	/// Replaces the contained value with `val`, and returns the old contained value.
+	#[must_use = "if you don't need the old value, use `set` instead"]
+	pub fn replace(&self, val: T) -> T {
+		self.change(|old| mem::replace(old, val))
+	}
+
+	/// Replaces the contained value with one computed from it, and returns the old contained value.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(2);
+	/// assert_eq!(cell.replace_with(|v| *v * 2), 2);
+	/// assert_eq!(cell.get(), 4)
+	/// ```
+	#[must_use = "if you don't need the old value, use `update` instead"]
+	pub fn replace_with<F>(&self, function: F) -> T
+	where
+		F: FnOnce(&T) -> T,
+	{
+		self.write(|old| {
+			let new = function(old);
+			mem::replace(old, new)
+		})
+	}
+
+	/// Swaps the values of two `MutexCell`s.
+	///
+	/// The two cells are locked in a consistent order, so `a.swap(&b)` and `b.swap(&a)` running at the same time cannot deadlock.
+	pub fn swap(&self, new: &MutexCell<T>) {
+		let _ = self.swap_checked(new);
+	}
+
+	/// Swaps the values of two `MutexCell`s, returning whether a swap happened.
+	///
+	/// Returns `false`, without locking anything, if `new` is this same cell.
+	/// The two cells are locked in a consistent order, so concurrent swaps cannot deadlock.
+	#[must_use = "the returned value tells whether the swap happened"]
+	pub fn swap_checked(&self, new: &MutexCell<T>) -> bool {
+		if ptr::eq(self, new) {
+			return false;
+		}
+		lock2(&self.data, &new.data, |a, b| {
+			self.generation.fetch_add(1, Ordering::Release);
+			new.generation.fetch_add(1, Ordering::Release);
+			mem::swap(a, b)
+		});
+		true
+	}
+
+	/// Moves this cell's value into `dest`, leaving [`Default::default()`] behind, and returns the value `dest` held before.
+	///
+	/// Both cells are locked at once, in a consistent order, so concurrent moves cannot deadlock.
+	/// Moving a cell into itself leaves it unchanged, without locking it, and returns `T::default()`.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let from = MutexCell::new(1);
+	/// let to = MutexCell::new(2);
+	/// assert_eq!(from.move_into(&to), 2);
+	/// assert_eq!((from.get(), to.get()), (0, 1))
+	/// ```
+	pub fn move_into(&self, dest: &MutexCell<T>) -> T
+	where
+		T: Default,
+	{
+		if ptr::eq(self, dest) {
+			return T::default();
+		}
+		lock2(&self.data, &dest.data, |from, to| {
+			self.generation.fetch_add(1, Ordering::Release);
+			dest.generation.fetch_add(1, Ordering::Release);
+			mem::replace(to, mem::take(from))
+		})
+	}
+
+	/// Swaps the values of two `MutexCell`s if both can be locked right away, returning whether a swap happened.
+	///
+	/// Never blocks: if either cell is already locked, nothing is changed and `false` is returned.
+	/// Since neither lock is waited on, the order they are tried in cannot cause a deadlock.
+	/// Like [`swap_checked`](MutexCell::swap_checked), returns `false` if `other` is this same cell.
+	#[must_use = "the returned value tells whether the swap happened"]
+	pub fn try_swap(&self, other: &MutexCell<T>) -> bool {
+		if ptr::eq(self, other) {
+			return false;
+		}
+		let mut a = match self.data.try_acquire() {
+			Some(lock) => lock,
+			None => return false,
+		};
+		let mut b = match other.data.try_acquire() {
+			Some(lock) => lock,
+			None => return false,
+		};
+		self.generation.fetch_add(1, Ordering::Release);
+		other.generation.fetch_add(1, Ordering::Release);
+		mem::swap(&mut *a, &mut *b);
+		true
+	}
+
+	/// Sets the contained value to `temp` until the returned guard is dropped, then puts the old value back.
+	///
+	/// The old value is restored even if the scope is left by a panic.
+	/// Anything written to the cell while the guard is alive is overwritten when it drops.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let verbose = MutexCell::new(false);
+	/// {
+	///     let _guard = verbose.set_scoped(true);
+	///     assert!(verbose.get());
+	/// }
+	/// assert!(!verbose.get())
+	/// ```
+	pub fn set_scoped(&self, temp: T) -> RestoreGuard<'_, T>
+	where
+		T: Copy,
+	{
+		RestoreGuard {
+			cell: self,
+			old: self.replace(temp),
+		}
+	}
+
+	/// Takes the value of the cell, leaving `Default::default()` in its place.
+	///
+	/// For a `MutexCell<Option<V>>` this takes the contained option, leaving `None`, whatever `V` is.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(Some(String::from("job")));
+	/// assert_eq!(cell.take(), Some(String::from("job")));
+	/// assert_eq!(cell.take(), None)
+	/// ```
+	#[must_use = "if you don't need the old value, use `set` instead"]
+	pub fn take(&self) -> T
+	where
+		T: Default,
+	{
+		self.replace(T::default())
+	}
+
+	/// Takes the value of the cell, leaving the result of `f` in its place.
+	///
+	/// Like [`take`](MutexCell::take), but without needing `Default`. `f` is only called once the cell is locked.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(vec![1, 2]);
+	/// assert_eq!(cell.take_or_else(|| vec![0]), vec![1, 2]);
+	/// assert_eq!(cell.into_inner(), vec![0])
+	/// ```
+	#[must_use = "if you don't need the old value, use `set` instead"]
+	pub fn take_or_else<F>(&self, f: F) -> T
+	where
+		F: FnOnce() -> T,
+	{
+		self.write(|old| mem::replace(old, f()))
+	}
+
+	/// Runs the function with the cell locked, like [`WithLock::with_lock`], and returns its result.
+	///
+	/// Use this to group several reads and writes into one atomic step.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let counter = MutexCell::new(1);
+	/// let new = counter.scope(|v| {
+	///     *v += 1;
+	///     *v
+	/// });
+	/// assert_eq!(new, 2)
+	/// ```
+	pub fn scope<F, U>(&self, function: F) -> U
+	where
+		F: FnOnce(&mut T) -> U,
+	{
+		self.write(function)
+	}
+
+	/// Locks the cell if it is free, returning a guard that unlocks it when dropped, or `None` if it is held elsewhere.
+	///
+	/// The guard can change the value freely, so the [`generation`](MutexCell::generation) advances as soon as it is taken.
+	/// [`on_change`](MutexCell::on_change) callbacks are not run for changes made through it.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(1);
+	/// if let Some(mut guard) = cell.try_lock_guard() {
+	///     *guard += 1;
+	/// }
+	/// assert_eq!(cell.get(), 2)
+	/// ```
+	pub fn try_lock_guard(&self) -> Option<WithLockGuard<'_, T>> {
+		let guard = self.data.try_lock()?;
+		self.generation.fetch_add(1, Ordering::Release);
+		Some(guard)
+	}
+
+	/// Runs each function on the contained value in order, all under a single lock.
+	///
+	/// Other threads never see the value between two of the functions, so the batch applies atomically.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(1);
+	/// let mut steps: [fn(&mut i32); 2] = [|v| *v += 1, |v| *v *= 10];
+	/// cell.apply_all(&mut steps);
+	/// assert_eq!(cell.get(), 20)
+	/// ```
+	pub fn apply_all<F>(&self, functions: &mut [F])
+	where
+		F: FnMut(&mut T),
+	{
+		self.write(|s| {
+			for function in functions {
+				function(s);
+			}
+		})
+	}
+
+	/// Mutates the contained value in place, holding the lock once for the whole update.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let counter = MutexCell::new(1);
+	/// counter.update(|v| *v += 1);
+	/// assert_eq!(counter.get(), 2)
+	/// ```
+	pub fn update<F>(&self, function: F)
+	where
+		F: FnOnce(&mut T),
+	{
+		self.change(function)
+	}
+
+	/// Mutates the contained value in place and returns a copy of the result, holding the lock once.
+	///
+	/// This is the counterpart to the `fetch_*` methods, which return the old value.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let counter = MutexCell::new(1);
+	/// assert_eq!(counter.update_and_get(|v| *v += 1), 2)
+	/// ```
+	pub fn update_and_get<F>(&self, function: F) -> T
+	where
+		F: FnOnce(&mut T),
+		T: Copy,
+	{
+		self.write(|s| {
+			function(s);
+			*s
+		})
+	}
+
+	/// Computes a value from a shared reference to the contained value, without copying it.
+	///
+	/// Unlike [`scope`](MutexCell::scope), the function only gets `&T`, which is all a read needs.
+	/// This is the `MutexCell` counterpart to [`WithLock::with_lock_ref`].
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(vec![1, 2, 3]);
+	/// assert_eq!(cell.map(|v| v.len()), 3)
+	/// ```
+	#[doc(alias = "with_ref")]
+	pub fn map<U, F>(&self, function: F) -> U
+	where
+		F: FnOnce(&T) -> U,
+	{
+		self.data.with_lock(|s| function(s))
+	}
+
+	/// Runs the function with a shared reference to the contained value, then returns the cell for chaining.
+	///
+	/// The lock is released before `inspect` returns, so a chained call locks the cell again.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(1);
+	/// cell.inspect(|v| println!("replacing {}", v)).set(2);
+	/// assert_eq!(cell.get(), 2)
+	/// ```
+	pub fn inspect<F>(&self, function: F) -> &MutexCell<T>
+	where
+		F: FnOnce(&T),
+	{
+		self.map(function);
+		self
+	}
+
+	/// Applies `f` only if `predicate` holds for the contained value, returning whether it did.
+	///
+	/// The check and the modification happen under a single lock.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(1);
+	/// assert!(cell.modify_if(|v| *v < 2, |v| *v += 1));
+	/// assert!(!cell.modify_if(|v| *v < 2, |v| *v += 1));
+	/// assert_eq!(cell.get(), 2)
+	/// ```
+	pub fn modify_if<P, F>(&self, predicate: P, f: F) -> bool
+	where
+		P: FnOnce(&T) -> bool,
+		F: FnOnce(&mut T),
+	{
+		self.write(|s| {
+			let modify = predicate(s);
+			if modify {
+				f(s);
+			}
+			modify
+		})
+	}
+
+	/// Stores `new` if the contained value equals `current`, all under a single lock.
+	///
+	/// The previous value is always returned, so the swap happened if it equals `current`.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(1);
+	/// assert_eq!(cell.compare_and_swap(1, 2), 1);
+	/// assert_eq!(cell.compare_and_swap(1, 3), 2);
+	/// assert_eq!(cell.get(), 2)
+	/// ```
+	#[must_use = "the returned value tells whether the swap happened"]
+	pub fn compare_and_swap(&self, current: T, new: T) -> T
+	where
+		T: Copy + PartialEq,
+	{
+		self.write(|s| {
+			let old = *s;
+			if old == current {
+				*s = new;
+			}
+			old
+		})
+	}
+
+	/// Like [`compare_and_swap`](MutexCell::compare_and_swap), but never blocks.
+	///
+	/// Returns `None` without comparing anything if the cell is currently locked, so the caller can retry or do something else.
+	/// Otherwise returns `Some` with the previous value, and the swap happened if it equals `current`.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(1);
+	/// assert_eq!(cell.try_compare_and_swap(1, 2), Some(1));
+	/// assert_eq!(cell.get(), 2)
+	/// ```
+	#[must_use = "the returned value tells whether the swap happened"]
+	pub fn try_compare_and_swap(&self, current: T, new: T) -> Option<T>
+	where
+		T: Copy + PartialEq,
+	{
+		self.data.try_with_lock(|s| {
+			self.generation.fetch_add(1, Ordering::Release);
+			let old = *s;
+			if old == current {
+				*s = new;
+			}
+			old
+		})
+	}
+
+	/// Adds `val` to the contained value, returning the previous value.
+	///
+	/// Overflow behaves like the `+` operator; use [`Wrapping`](core::num::Wrapping) for wrapping arithmetic.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(1);
+	/// assert_eq!(cell.fetch_add(2), 1);
+	/// assert_eq!(cell.get(), 3)
+	/// ```
+	#[must_use = "if you don't need the old value, use `update` instead"]
+	pub fn fetch_add(&self, val: T) -> T
+	where
+		T: Copy + Add<Output = T>,
+	{
+		self.write(|s| mem::replace(s, *s + val))
+	}
+
+	/// Subtracts `val` from the contained value, returning the previous value.
+	///
+	/// Overflow behaves like the `-` operator; use [`Wrapping`](core::num::Wrapping) for wrapping arithmetic.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(3);
+	/// assert_eq!(cell.fetch_sub(2), 3);
+	/// assert_eq!(cell.get(), 1)
+	/// ```
+	#[must_use = "if you don't need the old value, use `update` instead"]
+	pub fn fetch_sub(&self, val: T) -> T
+	where
+		T: Copy + Sub<Output = T>,
+	{
+		self.write(|s| mem::replace(s, *s - val))
+	}
+
+	/// Sets the contained value to the minimum of itself and `val`, returning the previous value.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(3);
+	/// assert_eq!(cell.fetch_min(5), 3);
+	/// assert_eq!(cell.fetch_min(1), 3);
+	/// assert_eq!(cell.get(), 1)
+	/// ```
+	#[must_use = "if you don't need the old value, use `update` instead"]
+	pub fn fetch_min(&self, val: T) -> T
+	where
+		T: Copy + Ord,
+	{
+		self.write(|s| mem::replace(s, cmp::min(*s, val)))
+	}
+
+	/// Sets the contained value to the maximum of itself and `val`, returning the previous value.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(3);
+	/// assert_eq!(cell.fetch_max(1), 3);
+	/// assert_eq!(cell.fetch_max(5), 3);
+	/// assert_eq!(cell.get(), 5)
+	/// ```
+	#[must_use = "if you don't need the old value, use `update` instead"]
+	pub fn fetch_max(&self, val: T) -> T
+	where
+		T: Copy + Ord,
+	{
+		self.write(|s| mem::replace(s, cmp::max(*s, val)))
+	}
+
+	/// Stores the value returned by `f` if it is `Some`, similar to [`AtomicUsize::fetch_update`](core::sync::atomic::AtomicUsize::fetch_update).
+	///
+	/// Returns `Ok(previous)` if the value was updated, or `Err(current)` if `f` returned `None`.
+	/// Since this happens under a single lock, `f` runs exactly once and never needs a retry loop.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(7);
+	/// assert_eq!(cell.fetch_update(|v| if v < 10 { Some(v + 1) } else { None }), Ok(7));
+	/// assert_eq!(cell.fetch_update(|_| None), Err(8));
+	/// ```
+	pub fn fetch_update<F>(&self, f: F) -> Result<T, T>
+	where
+		F: FnOnce(T) -> Option<T>,
+		T: Copy,
+	{
+		self.write(|s| {
+			let old = *s;
+			match f(old) {
+				Some(new) => {
+					*s = new;
+					Ok(old)
+				}
+				None => Err(old),
+			}
+		})
+	}
+
+	/// Unwraps the value.
+	#[must_use]
+	pub fn into_inner(self) -> T {
+		self.data.data.into_inner()
+	}
+}
+
+/// A guard returned by [`MutexCell::set_scoped`]. The old value is put back when it is dropped.
+#[must_use = "if unused the old value is restored immediately"]
+pub struct RestoreGuard<'a, T: Copy> {
+	pub(crate) cell: &'a MutexCell<T>,
+	pub(crate) old: T,
+}
+
+impl<T: Copy> Drop for RestoreGuard<'_, T> {
+	fn drop(&mut self) {
+		self.cell.set(self.old);
+	}
+}
+
+impl<V> MutexCell<Option<V>> {
+	/// Returns a clone of the contained value, first initializing it with `f` if it is `None`.
+	///
+	/// The check and the initialization happen under a single lock, so `f` runs at most once even if many threads race.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(None);
+	/// assert_eq!(cell.get_or_insert_with(|| 1), 1);
+	/// assert_eq!(cell.get_or_insert_with(|| 2), 1)
+	/// ```
+	pub fn get_or_insert_with<F>(&self, f: F) -> V
+	where
+		F: FnOnce() -> V,
+		V: Clone,
+	{
+		self.write(|s| s.get_or_insert_with(f).clone())
+	}
+}
+
+impl MutexCell<bool> {
+	/// Flips the flag, returning the new value.
+	pub fn toggle(&self) -> bool {
+		self.change(|s| {
+			*s = !*s;
+			*s
+		})
+	}
+
+	/// Sets the flag to `true`, returning whether it changed.
+	pub fn set_true(&self) -> bool {
+		!self.replace(true)
+	}
+
+	/// Sets the flag to `false`, returning whether it changed.
+	pub fn set_false(&self) -> bool {
+		self.replace(false)
+	}
+}
+
+/// A thread-safe stack.
+#[cfg(feature = "std")]
+impl<T> MutexCell<Vec<T>> {
+	/// Appends an item to the back of the vector.
+	pub fn push(&self, item: T) {
+		self.write(|v| v.push(item))
+	}
+
+	/// Removes the last item from the vector and returns it, or `None` if it is empty.
+	pub fn pop(&self) -> Option<T> {
+		self.write(|v| v.pop())
+	}
+
+	/// Takes every item out of the vector in one lock, leaving it empty.
+	pub fn drain_all(&self) -> Vec<T> {
+		self.write(mem::take)
+	}
+
+	/// Calls the function on each item in order, holding the lock for the whole iteration.
+	///
+	/// Other threads wait until the iteration is done, so the function sees a consistent snapshot.
+	/// ## Example
+	/// ```rust
+	/// use with_lock::MutexCell;
+	/// let cell = MutexCell::new(vec![1, 2, 3]);
+	/// let mut sum = 0;
+	/// cell.for_each(|v| sum += v);
+	/// assert_eq!(sum, 6)
+	/// ```
+	pub fn for_each<F>(&self, function: F)
+	where
+		F: FnMut(&T),
+	{
+		self.data.with_lock(|v| v.iter().for_each(function))
+	}
+
+	/// Keeps only the items for which the function returns `true`, in one lock.
+	pub fn retain<F>(&self, function: F)
+	where
+		F: FnMut(&T) -> bool,
+	{
+		self.write(|v| v.retain(function))
+	}
+
+	/// Returns the number of items in the vector.
+	#[must_use]
+	pub fn len(&self) -> usize {
+		self.data.with_lock(|v| v.len())
+	}
+
+	/// Returns whether the vector is empty.
+	#[must_use]
+	pub fn is_empty(&self) -> bool {
+		self.data.with_lock(|v| v.is_empty())
+	}
+
+	/// Returns a copy of the item at `index`, or `None` if it is out of bounds.
+	#[must_use]
+	pub fn get_index(&self, index: usize) -> Option<T>
+	where
+		T: Copy,
+	{
+		self.data.with_lock(|v| v.get(index).copied())
+	}
+
+	/// Replaces the item at `index`, returning `false` without changing anything if it is out of bounds.
+	pub fn set_index(&self, index: usize, item: T) -> bool {
+		self.write(|v| match v.get_mut(index) {
+			Some(slot) => {
+				*slot = item;
+				true
+			}
+			None => false,
+		})
+	}
+}
+
+/// A thread-safe string builder.
+#[cfg(feature = "std")]
+impl MutexCell<String> {
+	/// Appends a string slice to the end of the string.
+	pub fn push_str(&self, string: &str) {
+		self.write(|s| s.push_str(string))
+	}
+
+	/// Appends a character to the end of the string.
+	pub fn push_char(&self, ch: char) {
+		self.write(|s| s.push(ch))
+	}
+
+	/// Empties the string, keeping its capacity.
+	pub fn clear(&self) {
+		self.write(|s| s.clear())
+	}
+}
+
+/// A thread-safe time accumulator.
+impl MutexCell<Duration> {
+	/// Adds `duration` to the total.
+	pub fn add_duration(&self, duration: Duration) {
+		self.write(|total| *total += duration)
+	}
+
+	/// Adds the time elapsed since `start` to the total, and returns that elapsed time.
+	/// ## Example
+	/// ```rust
+	/// use std::time::{Duration, Instant};
+	/// use with_lock::MutexCell;
+	/// let busy = MutexCell::new(Duration::ZERO);
+	/// let start = Instant::now();
+	/// let elapsed = busy.elapsed_since(start);
+	/// assert_eq!(busy.get(), elapsed)
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn elapsed_since(&self, start: Instant) -> Duration {
+		let elapsed = start.elapsed();
+		self.add_duration(elapsed);
+		elapsed
+	}
+}
+
+/// A thread-safe map. The lock is released before each method returns.
+#[cfg(feature = "std")]
+impl<K: Eq + Hash, V, S: BuildHasher> MutexCell<HashMap<K, V, S>> {
+	/// Inserts a key-value pair, returning the value previously stored under the key.
+	pub fn insert(&self, key: K, value: V) -> Option<V> {
+		self.write(|m| m.insert(key, value))
+	}
+
+	/// Returns a clone of the value stored under the key.
+	#[must_use]
+	pub fn get_cloned<Q>(&self, key: &Q) -> Option<V>
+	where
+		K: Borrow<Q>,
+		Q: Hash + Eq + ?Sized,
+		V: Clone,
+	{
+		self.data.with_lock(|m| m.get(key).cloned())
+	}
+
+	/// Removes a key, returning the value that was stored under it.
+	pub fn remove<Q>(&self, key: &Q) -> Option<V>
+	where
+		K: Borrow<Q>,
+		Q: Hash + Eq + ?Sized,
+	{
+		self.write(|m| m.remove(key))
+	}
+}
+
+/// Creates a new, independent `MutexCell` holding a clone of the current value.
+///
+/// The clone does not share the original's mutex: changes to one are not visible through the other.
+impl<T: Clone> Clone for MutexCell<T> {
+	fn clone(&self) -> MutexCell<T> {
+		MutexCell::new(self.data.with_lock(|s| s.clone()))
+	}
+}
+
+impl<T: Default> Default for MutexCell<T> {
+	fn default() -> MutexCell<T> {
+		MutexCell::new(T::default())
+	}
+}
+
+impl<T> From<T> for MutexCell<T> {
+	fn from(data: T) -> MutexCell<T> {
+		MutexCell::new(data)
+	}
+}
+
+macro_rules! impl_op_assign {
+	($($trait:ident $method:ident),*) => {
+		$(
+			impl<T: $trait> $trait<T> for MutexCell<T> {
+				fn $method(&mut self, rhs: T) {
+					self.get_mut().$method(rhs);
+				}
+			}
+
+			/// Applies the operation under a single lock, so it is safe to use from several threads at once.
+			impl<T: $trait> $trait<T> for &MutexCell<T> {
+				fn $method(&mut self, rhs: T) {
+					self.update(|v| v.$method(rhs));
+				}
+			}
+		)*
+	};
+}
+
+impl_op_assign!(
+	AddAssign add_assign,
+	SubAssign sub_assign,
+	MulAssign mul_assign,
+	DivAssign div_assign
+);
+
+macro_rules! impl_op {
+	($($trait:ident $method:ident),*) => {
+		$(
+			/// Reads both cells, locking them in a consistent order, and returns a new cell holding the result.
+			///
+			/// Using the same cell on both sides only locks it once.
+			impl<T: Copy + $trait<Output = T>> $trait for &MutexCell<T> {
+				type Output = MutexCell<T>;
+
+				fn $method(self, rhs: &MutexCell<T>) -> MutexCell<T> {
+					let (a, b) = if ptr::eq(self, rhs) {
+						let v = self.get();
+						(v, v)
+					} else {
+						lock2(&self.data, &rhs.data, |a, b| (*a, *b))
+					};
+					MutexCell::new(a.$method(b))
+				}
+			}
+		)*
+	};
+}
+
+impl_op!(Add add, Sub sub, Mul mul);
+
+/// Compares the contained values, locking both cells in a consistent order.
+///
+/// Comparing a cell with itself only locks it once.
+impl<T: PartialEq> PartialEq for MutexCell<T> {
+	fn eq(&self, other: &MutexCell<T>) -> bool {
+		if ptr::eq(self, other) {
+			return self.map(|s| s.eq(s));
+		}
+		lock2(&self.data, &other.data, |a, b| a == b)
+	}
+}
+
+impl<T: Eq> Eq for MutexCell<T> {}
+
+/// Orders cells by their contained values, locking both cells in a consistent order.
+///
+/// Comparing a cell with itself only locks it once.
+/// The values can change as soon as the comparison returns, so keep cells that are being sorted, or stored in an ordered collection, unchanged.
+impl<T: PartialOrd> PartialOrd for MutexCell<T> {
+	fn partial_cmp(&self, other: &MutexCell<T>) -> Option<cmp::Ordering> {
+		if ptr::eq(self, other) {
+			return self.map(|s| s.partial_cmp(s));
+		}
+		lock2(&self.data, &other.data, |a, b| (*a).partial_cmp(b))
+	}
+}
+
+impl<T: Ord> Ord for MutexCell<T> {
+	fn cmp(&self, other: &MutexCell<T>) -> cmp::Ordering {
+		if ptr::eq(self, other) {
+			return self.map(|s| s.cmp(s));
+		}
+		lock2(&self.data, &other.data, |a, b| (*a).cmp(b))
+	}
+}
+
+/// Hashes the contained value, holding the lock only while it is being hashed.
+///
+/// Changing a cell that is used as a key in a `HashMap` or `HashSet` breaks the collection,
+/// just like changing a key through interior mutability would.
+impl<T: Hash> Hash for MutexCell<T> {
+	fn hash<H: Hasher>(&self, state: &mut H) {
+		self.map(|s| s.hash(state))
+	}
+}
+
+impl<T: fmt::Debug> fmt::Debug for MutexCell<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.data.fmt_as("MutexCell", f)
+	}
+}
+
+/// Displays the contained value, or `<locked>` if the cell is currently locked, since blocking inside a formatter is asking for a deadlock.
+impl<T: fmt::Display> fmt::Display for MutexCell<T> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self.data.try_acquire() {
+			Some(guard) => fmt::Display::fmt(&*guard, f),
+			None => f.write_str("<locked>"),
+		}
+	}
+}
+
+/// Serializes the contained value, holding the lock only while it is being serialized.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for WithLock<T> {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: serde::Serializer,
+	{
+		self.with_lock(|s| s.serialize(serializer))
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for WithLock<T> {
+	fn deserialize<D>(deserializer: D) -> Result<WithLock<T>, D::Error>
+	where
+		D: serde::Deserializer<'de>,
+	{
+		T::deserialize(deserializer).map(WithLock::<T>::new)
+	}
+}
+
+/// Serializes the contained value, holding the lock only while it is being serialized.
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize> serde::Serialize for MutexCell<T> {
+	fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+	where
+		S: serde::Serializer,
+	{
+		self.data.serialize(serializer)
+	}
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: serde::Deserialize<'de>> serde::Deserialize<'de> for MutexCell<T> {
+	fn deserialize<D>(deserializer: D) -> Result<MutexCell<T>, D::Error>
+	where
+		D: serde::Deserializer<'de>,
+	{
+		T::deserialize(deserializer).map(MutexCell::new)
+	}
+}
+
+/// Locks a [`WithLock`] or [`MutexCell`] and runs a closure with the value, or locks two of them at once.
+///
+/// `with_lock!(lock => |v| ..)` is [`WithLock::with_lock`] or [`MutexCell::scope`].
+/// `with_lock!(a, b => |a, b| ..)` locks both in a consistent order like [`lock2`], and panics if they are the same lock.
+/// Either side can be a `WithLock`, a `MutexCell`, or a reference or [`Arc`](std::sync::Arc) to one.
+/// ## Examples
+/// ```rust
+/// use with_lock::{with_lock, MutexCell, WithLock};
+/// let balance = WithLock::new(10);
+/// let fees = MutexCell::new(1);
+/// with_lock!(balance => |b| *b += 5);
+/// let total = with_lock!(balance, fees => |b, f| {
+///     *b -= *f;
+///     *b
+/// });
+/// assert_eq!(total, 14);
+/// ```
+#[macro_export]
+macro_rules! with_lock {
+	($lock:expr => $function:expr) => {
+		$crate::__private::lock_with(&$lock, $function)
+	};
+	($a:expr, $b:expr => $function:expr) => {
+		$crate::__private::lock2_with(&$a, &$b, $function)
+	};
+}
+
+#[doc(hidden)]
+pub mod __private {
+	use super::*;
+
+	/// What [`with_lock!`](crate::with_lock) can lock.
+	pub trait Lockable {
+		type Target;
+
+		fn lock_target(&self) -> &WithLock<Self::Target>;
+
+		/// Called under the lock when the value is handed out mutably.
+		fn changed(&self) {}
+	}
+
+	impl<T> Lockable for WithLock<T> {
+		type Target = T;
+
+		fn lock_target(&self) -> &WithLock<T> {
+			self
+		}
+	}
+
+	impl<T> Lockable for MutexCell<T> {
+		type Target = T;
+
+		fn lock_target(&self) -> &WithLock<T> {
+			&self.data
+		}
+
+		fn changed(&self) {
+			self.generation.fetch_add(1, Ordering::Release);
+		}
+	}
+
+	impl<L: Lockable + ?Sized> Lockable for &L {
+		type Target = L::Target;
+
+		fn lock_target(&self) -> &WithLock<L::Target> {
+			(**self).lock_target()
+		}
+
+		fn changed(&self) {
+			(**self).changed()
+		}
+	}
+
+	#[cfg(feature = "std")]
+	impl<L: Lockable + ?Sized> Lockable for Arc<L> {
+		type Target = L::Target;
+
+		fn lock_target(&self) -> &WithLock<L::Target> {
+			(**self).lock_target()
+		}
+
+		fn changed(&self) {
+			(**self).changed()
+		}
+	}
+
+	pub fn lock_with<L, F, R>(lock: &L, function: F) -> R
+	where
+		L: Lockable,
+		F: FnOnce(&mut L::Target) -> R,
+	{
+		lock.lock_target().with_lock(|s| {
+			lock.changed();
+			function(s)
+		})
+	}
+
+	pub fn lock2_with<A, B, F, R>(a: &A, b: &B, function: F) -> R
+	where
+		A: Lockable,
+		B: Lockable,
+		F: FnOnce(&mut A::Target, &mut B::Target) -> R,
+	{
+		lock2(a.lock_target(), b.lock_target(), |x, y| {
+			a.changed();
+			b.changed();
+			function(x, y)
+		})
+	}
+}
+
+// Compile-time checks that the lock types can be sent and shared between threads.
+// `WithRwLock` and its relatives hand out `&T` to several threads at once, so they also need `T: Sync`.
+#[allow(dead_code)]
+const _: () = {
+	fn assert_send_sync<S: Send + Sync>() {}
+
+	fn mutex_types<T: Send>() {
+		assert_send_sync::<WithLock<T>>();
+		assert_send_sync::<MutexCell<T>>();
+		#[cfg(feature = "std")]
+		assert_send_sync::<ReentrantWithLock<T>>();
+		#[cfg(feature = "async")]
+		assert_send_sync::<AsyncWithLock<T>>();
+	}
+
+	fn rw_lock_types<T: Send + Sync>() {
+		assert_send_sync::<WithRwLock<T>>();
+	}
+};
+
+#[cfg(doctest)]
+#[doc = include_str!("../README.md")]
+mod readme {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+	use crate::*;
+	use std::sync::{mpsc, Arc};
+	use std::thread;
+
+	static COUNTER: MutexCell<u64> = MutexCell::new(0);
+	static LOCK: WithLock<u64> = WithLock::<u64>::new(0);
+
+	struct SharedData {
+		pub a: i64,
+		pub b: i64,
+	}
+
+	#[test]
+	fn test_with_lock() {
+		let a = WithLock::<i64>::new(2);
+		let b = WithLock::<i64>::new(3);
+
+		let action_and_get = |s: &mut i64| *s;
+		let a_lock = a.with_lock(action_and_get);
+		let b_lock = b.with_lock(action_and_get);
+		assert_eq!(a_lock + b_lock, 5);
+
+		// repeat action with embedded lambda expression
+		let a_lock_2 = a.with_lock(|s| *s);
+		let b_lock_2 = b.with_lock(|s| *s);
+		assert_eq!(a_lock_2 + b_lock_2, 5);
+	}
+
+	/// Runs `check` on the current thread while `hold` keeps another thread inside a critical section.
+	fn while_held<H, C, U>(hold: H, check: C) -> U
+	where
+		H: FnOnce(&dyn Fn()) + Send + 'static,
+		C: FnOnce() -> U,
+	{
+		let (locked_tx, locked_rx) = mpsc::channel();
+		let (release_tx, release_rx) = mpsc::channel::<()>();
+		let handle = thread::spawn(move || {
+			hold(&|| {
+				locked_tx.send(()).unwrap();
+				release_rx.recv().unwrap();
+			})
+		});
+		locked_rx.recv().unwrap();
+		let result = check();
+		release_tx.send(()).unwrap();
+		handle.join().unwrap();
+		result
+	}
+
+	#[test]
+	// `counter` is `Copy`, so passing it by value would hand out fresh copies of `count`.
+	#[allow(clippy::needless_borrows_for_generic_args)]
+	fn test_with_lock_reused_closure() {
+		let a = WithLock::<i64>::new(0);
+		let mut count = 0;
+		let mut counter = move |s: &mut i64| {
+			count += 1;
+			*s = count;
+			count
+		};
+		assert_eq!(a.with_lock(&mut counter), 1);
+		assert_eq!(a.with_lock(&mut counter), 2);
+		assert_eq!(a.with_lock(|s| *s), 2);
+	}
+
+	#[test]
+	fn test_try_with_lock() {
+		let a = WithLock::<i64>::new(2);
+		assert_eq!(a.try_with_lock(|s| *s), Some(2));
+	}
+
+	#[test]
+	fn test_try_with_lock_contended() {
+		let lock = Arc::new(WithLock::<i64>::new(2));
+		let held = Arc::clone(&lock);
+		let mut ran = false;
+		let result = while_held(
+			move |wait| held.with_lock(|_| wait()),
+			|| {
+				lock.try_with_lock(|s| {
+					ran = true;
+					*s
+				})
+			},
+		);
+		assert_eq!(result, None);
+		assert!(!ran);
+		assert_eq!(lock.try_with_lock(|s| *s), Some(2));
+	}
+
+	#[cfg(feature = "std")]
+	fn hold_for(lock: &Arc<WithLock<i64>>, duration: Duration) -> thread::JoinHandle<()> {
+		let (locked_tx, locked_rx) = mpsc::channel();
+		let lock = Arc::clone(lock);
+		let handle = thread::spawn(move || {
+			lock.with_lock(|_| {
+				locked_tx.send(()).unwrap();
+				thread::sleep(duration);
+			})
+		});
+		locked_rx.recv().unwrap();
+		handle
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_with_lock_timeout_expires() {
+		let lock = Arc::new(WithLock::<i64>::new(2));
+		let handle = hold_for(&lock, Duration::from_millis(200));
+		let mut ran = false;
+		let result = lock.with_lock_timeout(Duration::from_millis(50), |s| {
+			ran = true;
+			*s
+		});
+		assert_eq!(result, None);
+		assert!(!ran);
+		handle.join().unwrap();
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_with_lock_timeout_acquires() {
+		let lock = Arc::new(WithLock::<i64>::new(2));
+		let handle = hold_for(&lock, Duration::from_millis(200));
+		let result = lock.with_lock_timeout(Duration::from_millis(500), |s| *s);
+		assert_eq!(result, Some(2));
+		handle.join().unwrap();
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_with_lock_timeout_zero() {
+		let lock = Arc::new(WithLock::<i64>::new(2));
+		assert_eq!(lock.with_lock_timeout(Duration::ZERO, |s| *s), Some(2));
+		let result = while_held(
+			{
+				let lock = Arc::clone(&lock);
+				move |wait| lock.with_lock(|_| wait())
+			},
+			|| lock.with_lock_timeout(Duration::ZERO, |s| *s),
+		);
+		assert_eq!(result, None);
+	}
+
+	#[test]
+	fn test_with_lock_spin() {
+		let lock = WithLock::<i64>::new(1);
+		let mut calls = 0;
+		lock.with_lock_spin(0, |s| {
+			calls += 1;
+			*s += 1
+		});
+		lock.with_lock_spin(10, |s| {
+			calls += 1;
+			*s += 1
+		});
+		assert_eq!(calls, 2);
+		assert_eq!(lock.with_lock(|s| *s), 3);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_with_lock_spin_falls_back_to_blocking() {
+		let lock = Arc::new(WithLock::<i64>::new(2));
+		let handle = hold_for(&lock, Duration::from_millis(100));
+		assert_eq!(lock.with_lock_spin(100, |s| *s), 2);
+		handle.join().unwrap();
+	}
+
+	#[test]
+	fn test_with_lock_spin_contended() {
+		let lock = Arc::new(WithLock::<u64>::new(0));
+		let handles: Vec<_> = (0..4)
+			.map(|_| {
+				let lock = Arc::clone(&lock);
+				thread::spawn(move || {
+					for _ in 0..10_000 {
+						lock.with_lock_spin(64, |s| *s += 1);
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		assert_eq!(lock.with_lock(|s| *s), 40_000);
+	}
+
+	#[test]
+	fn test_with_lock_fair() {
+		let lock = WithLock::<i64>::new(1);
+		assert_eq!(
+			lock.with_lock_fair(|s| {
+				*s += 1;
+				*s
+			}),
+			2
+		);
+		assert!(!lock.is_locked());
+	}
+
+	#[cfg(feature = "parking-lot")]
+	#[test]
+	fn test_with_lock_fair_hands_over_to_waiter() {
+		use std::time::Instant;
+
+		const RELEASES: u32 = 200;
+		let lock = Arc::new(WithLock::new(0u32));
+		// Counts how many of the greedy thread's releases it got in after.
+		let waiter = {
+			let lock = Arc::clone(&lock);
+			thread::spawn(move || {
+				let mut turns = 0;
+				let mut last = None;
+				loop {
+					let releases = lock.with_lock(|s| *s);
+					if last != Some(releases) {
+						turns += 1;
+						last = Some(releases);
+					}
+					if releases == RELEASES {
+						return turns;
+					}
+					thread::yield_now();
+				}
+			})
+		};
+		// Greedy: relocks as soon as it unlocks, so only a fair unlock lets the waiter in between.
+		for _ in 0..RELEASES {
+			lock.with_lock_fair(|s| {
+				// Short enough to stay clear of parking_lot's own eventual fairness,
+				// and yielding rather than spinning lets the waiter park even on a single core.
+				let start = Instant::now();
+				while start.elapsed() < Duration::from_micros(100) {
+					thread::yield_now();
+				}
+				*s += 1;
+			});
+		}
+		let turns = waiter.join().unwrap();
+		assert!(
+			turns >= RELEASES / 2,
+			"the waiter only got in after {} of {} releases",
+			turns,
+			RELEASES
+		);
+	}
+
+	#[cfg(feature = "parking-lot")]
+	#[test]
+	fn test_with_lock_from_mutex() {
+		let mutex = parking_lot::Mutex::new(vec![1]);
+		mutex.lock().push(2);
+		let lock = WithLock::from_mutex(mutex);
+		lock.with_lock(|s| s.push(3));
+		assert_eq!(lock.with_lock(|s| s.clone()), [1, 2, 3]);
+		assert!(lock.try_with_lock(|s| s.len()).is_some());
+	}
+
+	#[cfg(feature = "parking-lot")]
+	#[test]
+	fn test_with_lock_into_mutex() {
+		let lock = WithLock::new(vec![1]);
+		lock.with_lock(|s| s.push(2));
+		let mutex = lock.into_mutex();
+		mutex.lock().push(3);
+		assert_eq!(mutex.into_inner(), [1, 2, 3]);
+	}
+
+	#[test]
+	fn test_with_lock_ref() {
+		struct Config {
+			name: String,
+			retries: u32,
+		}
+
+		let lock = WithLock::new(Config {
+			name: String::from("api"),
+			retries: 3,
+		});
+		assert_eq!(lock.with_lock_ref(|c| c.name.len()), 3);
+		assert_eq!(lock.with_lock_ref(|c| c.retries), 3);
+		let describe = |c: &Config| format!("{} x{}", c.name, c.retries);
+		assert_eq!(lock.with_lock_ref(describe), "api x3");
+	}
+
+	#[cfg(feature = "deadlock-detection")]
+	#[test]
+	#[ignore = "leaves two threads deadlocked"]
+	fn test_deadlock_checker_reports_cycle() {
+		let a = Arc::new(WithLock::<i64>::new(0));
+		let b = Arc::new(WithLock::<i64>::new(0));
+		let barrier = Arc::new(std::sync::Barrier::new(2));
+		let ids: std::collections::HashSet<_> = (0..2)
+			.map(|i| {
+				let (first, second) = if i == 0 {
+					(Arc::clone(&a), Arc::clone(&b))
+				} else {
+					(Arc::clone(&b), Arc::clone(&a))
+				};
+				let barrier = Arc::clone(&barrier);
+				thread::spawn(move || {
+					first.with_lock(|_| {
+						barrier.wait();
+						second.with_lock(|_| ());
+					})
+				})
+				.thread()
+				.id()
+			})
+			.collect();
+		let (tx, rx) = mpsc::channel();
+		spawn_deadlock_checker_with(Duration::from_millis(50), move |deadlocks| {
+			let _ = tx.send(deadlocks);
+		});
+		let deadlocks = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+		assert_eq!(deadlocks.len(), 1);
+		let reported: std::collections::HashSet<_> =
+			deadlocks[0].iter().map(|t| t.thread_id).collect();
+		assert_eq!(reported, ids);
+	}
+
+	#[test]
+	fn test_try_with_lock_fallible() {
+		let a = WithLock::<i64>::new(2);
+		let result: Result<(), &str> = a.try_with_lock_fallible(|s| {
+			*s += 1;
+			Err("failed")
+		});
+		assert_eq!(result, Err("failed"));
+		assert_eq!(a.try_with_lock(|s| *s), Some(3));
+		assert_eq!(a.try_with_lock_fallible(|s| Ok::<_, ()>(*s)), Ok(3));
+	}
+
+	#[test]
+	fn test_lock_guard() {
+		let a = WithLock::<i64>::new(2);
+		let mut guard = a.lock();
+		*guard += 1;
+		assert_eq!(*guard, 3);
+		assert_eq!(a.try_with_lock(|s| *s), None);
+		drop(guard);
+		assert_eq!(a.with_lock(|s| *s), 3);
+	}
+
+	#[test]
+	fn test_rw_lock() {
+		let lock = WithRwLock::new(2);
+		assert_eq!(lock.read_with(|s| *s), 2);
+		lock.write_with(|s| *s += 1);
+		assert_eq!(lock.read_with(|s| *s), 3);
+	}
+
+	#[test]
+	fn test_rw_lock_concurrent_readers() {
+		let lock = Arc::new(WithRwLock::new(2));
+		let held = Arc::clone(&lock);
+		let (read, writable) = while_held(
+			move |wait| held.read_with(|_| wait()),
+			|| (lock.read_with(|s| *s), lock.data.try_write().is_some()),
+		);
+		assert_eq!(read, 2);
+		assert!(!writable);
+		assert!(lock.data.try_write().is_some());
+	}
+
+	#[cfg(feature = "async")]
+	#[tokio::test]
+	async fn test_async_with_lock() {
+		let lock = AsyncWithLock::new(2);
+		lock.with_lock_async(|s| *s += 1).await;
+		assert_eq!(lock.with_lock_async(|s| *s).await, 3);
+	}
+
+	#[cfg(feature = "async")]
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn test_async_with_lock_contended() {
+		let lock = Arc::new(AsyncWithLock::new(0));
+		let tasks: Vec<_> = (0..2)
+			.map(|_| {
+				let lock = Arc::clone(&lock);
+				tokio::spawn(async move {
+					for _ in 0..1000 {
+						lock.with_lock_async(|s| *s += 1).await;
+						tokio::task::yield_now().await;
+					}
+				})
+			})
+			.collect();
+		for task in tasks {
+			task.await.unwrap();
+		}
+		assert_eq!(lock.with_lock_async(|s| *s).await, 2000);
+	}
+
+	#[test]
+	fn test_is_locked() {
+		let lock = Arc::new(WithLock::<i64>::new(2));
+		assert!(!lock.is_locked());
+		let held = Arc::clone(&lock);
+		assert!(while_held(
+			move |wait| held.with_lock(|_| wait()),
+			|| lock.is_locked()
+		));
+		assert!(!lock.is_locked());
+	}
+
+	#[test]
+	fn test_lock2() {
+		let a = WithLock::<i64>::new(2);
+		let b = WithLock::<String>::new(String::from("b"));
+		let result = lock2(&a, &b, |a, b| {
+			*a += 1;
+			b.push('!');
+			format!("{}{}", a, b)
+		});
+		assert_eq!(result, "3b!");
+		assert_eq!(lock2(&b, &a, |b, a| (b.len(), *a)), (2, 3));
+	}
+
+	#[test]
+	fn test_with_two() {
+		let hits = WithLock::<u64>::new(3);
+		let name = WithLock::new(String::from("cache"));
+		let summary = hits.with_two(&name, |hits, name| {
+			*hits += 1;
+			format!("{}: {}", name, hits)
+		});
+		assert_eq!(summary, "cache: 4");
+		assert_eq!(
+			name.with_two(&hits, |name, hits| (name.len(), *hits)),
+			(5, 4)
+		);
+	}
+
+	#[test]
+	fn test_with_two_opposite_orders_dont_deadlock() {
+		let x = Arc::new(WithLock::<i64>::new(0));
+		let y = Arc::new(WithLock::<i64>::new(0));
+		let handles: Vec<_> = (0..4)
+			.map(|i| {
+				let x = Arc::clone(&x);
+				let y = Arc::clone(&y);
+				thread::spawn(move || {
+					for _ in 0..10_000 {
+						if i % 2 == 0 {
+							x.with_two(&y, mem::swap);
+						} else {
+							y.with_two(&x, |y, x| {
+								*x += 1;
+								*y += 1;
+							});
+						}
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		assert_eq!(x.with_two(&y, |x, y| *x + *y), 40_000);
+	}
+
+	#[test]
+	#[should_panic(expected = "same lock twice")]
+	fn test_lock2_same_lock() {
+		let a = WithLock::<i64>::new(2);
+		lock2(&a, &a, |_, _| ());
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_lock_many() {
+		let a = WithLock::new(1);
+		let b = WithLock::new(2);
+		let c = WithLock::new(3);
+		let seen = lock_many(&[&c, &a, &b], |values| {
+			for v in values.iter_mut() {
+				**v *= 10;
+			}
+			values.iter().map(|v| **v).collect::<Vec<_>>()
+		});
+		assert_eq!(seen, [30, 10, 20]);
+		assert_eq!(lock_many(&[], |values: &mut [&mut i32]| values.len()), 0);
+		assert!(!a.is_locked() && !b.is_locked() && !c.is_locked());
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	#[should_panic(expected = "same lock twice")]
+	fn test_lock_many_duplicate() {
+		let a = WithLock::new(1);
+		let b = WithLock::new(2);
+		lock_many(&[&a, &b, &a], |_| ());
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_lock_many_overlapping_orders_dont_deadlock() {
+		let locks: Arc<Vec<WithLock<u64>>> = Arc::new((0..4).map(|_| WithLock::new(0)).collect());
+		let subsets: [&[usize]; 4] = [&[0, 1, 2], &[2, 1, 3], &[3, 0], &[1, 3, 0, 2]];
+		let handles: Vec<_> = subsets
+			.iter()
+			.map(|subset| {
+				let locks = Arc::clone(&locks);
+				let subset = subset.to_vec();
+				thread::spawn(move || {
+					for _ in 0..5_000 {
+						let selected: Vec<_> = subset.iter().map(|&i| &locks[i]).collect();
+						lock_many(&selected, |values| {
+							for v in values.iter_mut() {
+								**v += 1;
+							}
+						});
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		let totals: Vec<_> = locks.iter().map(|l| l.with_lock(|s| *s)).collect();
+		assert_eq!(totals, [15_000, 15_000, 15_000, 15_000]);
+	}
+
+	#[test]
+	#[cfg(feature = "std")]
+	fn test_lock_owned() {
+		let lock = WithLock::new_shared(1);
+		let (locked_tx, locked_rx) = mpsc::channel();
+		let (release_tx, release_rx) = mpsc::channel::<()>();
+		let handle = {
+			let lock = Arc::clone(&lock);
+			thread::spawn(move || {
+				let mut guard = lock.lock_owned();
+				// The guard keeps the lock alive on its own.
+				drop(lock);
+				*guard += 1;
+				locked_tx.send(()).unwrap();
+				release_rx.recv().unwrap();
+				*guard += 1;
+			})
+		};
+		locked_rx.recv().unwrap();
+		assert!(lock.try_with_lock(|_| ()).is_none());
+		release_tx.send(()).unwrap();
+		handle.join().unwrap();
+		assert_eq!(lock.with_lock_owned(|s| *s), 3);
+		assert_eq!(Arc::strong_count(&lock), 1);
+	}
+
+	#[test]
+	#[cfg(feature = "std")]
+	fn test_lock_owned_is_static() {
+		fn keep<G: 'static>(guard: G) -> G {
+			guard
+		}
+		let lock = WithLock::new_shared(String::from("a"));
+		let mut guard = keep(lock.lock_owned());
+		guard.push('b');
+		assert!(Arc::ptr_eq(ArcWithLockGuard::lock(&guard), &lock));
+		drop(guard);
+		assert_eq!(lock.with_lock(|s| s.clone()), "ab");
+	}
+
+	#[test]
+	fn test_load_store() {
+		let cell = MutexCell::new(1);
+		assert_eq!(cell.load(), cell.get());
+		cell.store(2);
+		assert_eq!(cell.get(), 2);
+		assert_eq!(cell.generation(), 1);
+		cell.set(3);
+		assert_eq!(cell.load(), 3);
+		assert_eq!(cell.generation(), 2);
+	}
+
+	#[test]
+	fn test_with_lock_macro() {
+		let lock = WithLock::new(1);
+		let cell = MutexCell::new(String::from("a"));
+		with_lock!(lock => |v| *v += 1);
+		let len = with_lock!(cell => |s| {
+			s.push('b');
+			s.len()
+		});
+		assert_eq!(len, 2);
+		assert_eq!(cell.generation(), 1);
+		assert_eq!(with_lock!(&lock => |v| *v), 2);
+		#[cfg(feature = "std")]
+		{
+			let shared = Arc::new(WithLock::new(5));
+			assert_eq!(with_lock!(shared => |v| *v), 5);
+		}
+	}
+
+	#[test]
+	fn test_with_lock_macro_two() {
+		let a = WithLock::new(1);
+		let b = WithLock::new(2);
+		with_lock!(a, b => std::mem::swap);
+		assert_eq!(with_lock!(b, a => |b, a| (*a, *b)), (2, 1));
+
+		let cell = MutexCell::new(10);
+		let total = with_lock!(cell, a => |c, a| {
+			*c += *a;
+			*c
+		});
+		assert_eq!(total, 12);
+		assert_eq!(cell.generation(), 1);
+	}
+
+	#[test]
+	#[should_panic(expected = "same lock twice")]
+	fn test_with_lock_macro_same_lock() {
+		let cell = MutexCell::new(1);
+		with_lock!(cell, cell => |_, _| ());
+	}
+
+	#[test]
+	fn test_lock2_opposite_orders_dont_deadlock() {
+		let x = Arc::new(WithLock::<i64>::new(0));
+		let y = Arc::new(WithLock::<i64>::new(0));
+		let handles: Vec<_> = (0..4)
+			.map(|i| {
+				let x = Arc::clone(&x);
+				let y = Arc::clone(&y);
+				thread::spawn(move || {
+					for _ in 0..10_000 {
+						if i % 2 == 0 {
+							lock2(&x, &y, |x, y| {
+								*x += 1;
+								*y -= 1;
+							});
+						} else {
+							lock2(&y, &x, |y, x| {
+								*x += 1;
+								*y -= 1;
+							});
+						}
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		assert_eq!(x.with_lock(|s| *s), 40_000);
+		assert_eq!(y.with_lock(|s| *s), -40_000);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_with_lock_timed() {
+		let lock = Arc::new(WithLock::<i64>::new(2));
+		let (value, _) = lock.with_lock_timed(|s| *s);
+		assert_eq!(value, 2);
+
+		let handle = hold_for(&lock, Duration::from_millis(200));
+		let (value, waited) = lock.with_lock_timed(|s| *s);
+		assert_eq!(value, 2);
+		// Part of the hold may pass before we start waiting, so allow some slack.
+		assert!(waited >= Duration::from_millis(100), "waited {:?}", waited);
+		handle.join().unwrap();
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_with_lock_warn_with() {
+		let lock = WithLock::<i64>::new(2);
+		let mut warned = None;
+		let value = lock.with_lock_warn_with(
+			Duration::from_millis(20),
+			|s| {
+				thread::sleep(Duration::from_millis(50));
+				*s
+			},
+			|held| warned = Some(held),
+		);
+		assert_eq!(value, 2);
+		assert!(warned.unwrap() >= Duration::from_millis(50));
+
+		let mut warned = false;
+		lock.with_lock_warn_with(Duration::from_secs(10), |s| *s, |_| warned = true);
+		assert!(!warned);
+	}
+
+	#[cfg(feature = "log")]
+	#[test]
+	fn test_with_lock_warn() {
+		let lock = WithLock::<i64>::new(2);
+		assert_eq!(lock.with_lock_warn(Duration::from_secs(10), |s| *s), 2);
+	}
+
+	#[cfg(feature = "tracing")]
+	#[test]
+	fn test_with_lock_tracing_span() {
+		use tracing::field::{Field, Visit};
+		use tracing::span::{Attributes, Id, Record};
+		use tracing_subscriber::layer::{Context, SubscriberExt};
+		use tracing_subscriber::Layer;
+
+		#[derive(Default)]
+		struct Fields(Vec<(String, String)>);
+
+		impl Visit for Fields {
+			fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+				self.0
+					.push((field.name().to_string(), format!("{:?}", value)));
+			}
+		}
+
+		struct SpanLayer(Arc<std::sync::Mutex<Vec<(String, String)>>>);
+
+		impl<S: tracing::Subscriber> Layer<S> for SpanLayer {
+			fn on_new_span(&self, attrs: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+				let mut fields = Fields::default();
+				attrs.record(&mut fields);
+				self.0
+					.lock()
+					.unwrap()
+					.push((String::from("span"), attrs.metadata().name().to_string()));
+				self.0.lock().unwrap().extend(fields.0);
+			}
+
+			fn on_record(&self, _: &Id, values: &Record<'_>, _: Context<'_, S>) {
+				let mut fields = Fields::default();
+				values.record(&mut fields);
+				self.0.lock().unwrap().extend(fields.0);
+			}
+		}
+
+		let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+		let subscriber = tracing_subscriber::registry().with(SpanLayer(Arc::clone(&events)));
+		let lock = WithLock::<i64>::new(2);
+		let value = tracing::subscriber::with_default(subscriber, || lock.with_lock(|s| *s));
+		assert_eq!(value, 2);
+
+		let events = events.lock().unwrap();
+		let field = |name: &str| {
+			events
+				.iter()
+				.find(|(key, _)| key == name)
+				.map(|(_, value)| value.clone())
+		};
+		assert_eq!(field("span").as_deref(), Some("with_lock"));
+		assert_eq!(field("type").as_deref(), Some("\"i64\""));
+		assert!(field("wait_us").is_some());
+	}
+
+	#[cfg(feature = "metrics")]
+	#[test]
+	fn test_contention_count() {
+		let lock = Arc::new(WithLock::<i64>::new(0));
+		lock.with_lock(|s| *s += 1);
+		assert_eq!(lock.contention_count(), 0);
+
+		let handle = hold_for(&lock, Duration::from_millis(50));
+		lock.with_lock(|s| *s += 1);
+		handle.join().unwrap();
+		assert_eq!(lock.contention_count(), 1);
+
+		let handles: Vec<_> = (0..4)
+			.map(|_| {
+				let lock = Arc::clone(&lock);
+				thread::spawn(move || {
+					for _ in 0..1000 {
+						lock.with_lock(|s| *s += 1);
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		assert!(lock.contention_count() >= 1);
+	}
+
+	#[cfg(feature = "metrics")]
+	#[test]
+	fn test_contention_count_timeout() {
+		let lock = Arc::new(WithLock::<i64>::new(0));
+		assert_eq!(
+			lock.with_lock_timeout(Duration::from_millis(10), |s| *s),
+			Some(0)
+		);
+		assert_eq!(lock.contention_count(), 0);
+
+		let handle = hold_for(&lock, Duration::from_millis(50));
+		assert_eq!(
+			lock.with_lock_timeout(Duration::from_millis(1), |s| *s),
+			None
+		);
+		assert_eq!(
+			lock.with_lock_timeout(Duration::from_secs(5), |s| *s),
+			Some(0)
+		);
+		handle.join().unwrap();
+		assert_eq!(lock.contention_count(), 2);
+	}
+
+	#[cfg(feature = "metrics")]
+	#[test]
+	fn test_hold_stats() {
+		let lock = WithLock::<i64>::new(0);
+		assert_eq!(lock.hold_stats(), (0, Duration::ZERO));
+		for _ in 0..3 {
+			lock.with_lock(|s| {
+				thread::sleep(Duration::from_millis(10));
+				*s += 1
+			});
+		}
+		lock.try_with_lock(|s| *s += 1);
+		drop(lock.lock());
+		let (count, held) = lock.hold_stats();
+		assert_eq!(count, 5);
+		assert!(held >= Duration::from_millis(30));
+	}
+
+	#[cfg(feature = "metrics")]
+	#[test]
+	fn test_hold_stats_wait_until() {
+		let lock = Arc::new(WithLock::new(false));
+		let producer = Arc::clone(&lock);
+		let handle = thread::spawn(move || {
+			thread::sleep(Duration::from_millis(300));
+			producer.with_lock(|ready| *ready = true);
+			producer.notify_all();
+		});
+		lock.wait_until(|ready| *ready);
+		handle.join().unwrap();
+		let (count, held) = lock.hold_stats();
+		// At least the producer's hold, the first check and the final one.
+		assert!(count >= 3);
+		assert!(
+			held < Duration::from_millis(150),
+			"parked time counted: {:?}",
+			held
+		);
+	}
+
+	#[cfg(feature = "metrics")]
+	#[test]
+	fn test_hold_stats_formatting() {
+		let cell = MutexCell::new(1);
+		let _ = format!("{:?}", cell.data);
+		let _ = format!("{}", cell);
+		assert_eq!(cell.data.hold_stats().0, 2);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_wait_until() {
+		let counter = Arc::new(WithLock::<i64>::new(0));
+		let producer = Arc::clone(&counter);
+		let handle = thread::spawn(move || {
+			for _ in 0..10 {
+				producer.with_lock(|s| *s += 1);
+				producer.notify_all();
+				thread::sleep(Duration::from_millis(1));
+			}
+		});
+		let mut checks = 0;
+		counter.wait_until(|s| {
+			checks += 1;
+			*s >= 5
+		});
+		assert!(counter.with_lock(|s| *s) >= 5);
+		assert!(checks >= 1);
+		handle.join().unwrap();
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_with_lock_checked() {
+		let a = WithLock::<i64>::new(2);
+		assert_eq!(a.with_lock_checked(|s| *s), Ok(2));
+		assert!(!a.is_poisoned());
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_with_lock_checked_poisons() {
+		let a = WithLock::<i64>::new(2);
+		let result = a.with_lock_checked(|s| {
+			*s += 1;
+			panic!("inconsistent state");
+		});
+		assert_eq!(result, Err::<(), _>(LockPoisoned));
+		assert!(a.is_poisoned());
+
+		let mut ran = false;
+		assert_eq!(a.with_lock_checked(|_| ran = true), Err(LockPoisoned));
+		assert!(!ran);
+		// plain `with_lock` ignores poisoning
+		assert_eq!(a.with_lock(|s| *s), 3);
+
+		a.clear_poison();
+		assert!(!a.is_poisoned());
+		assert_eq!(a.with_lock_checked(|s| *s), Ok(3));
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_reentrant_with_lock() {
+		use std::cell::RefCell;
+
+		let lock = ReentrantWithLock::new(RefCell::new(2));
+		let result = lock.with_lock(|outer| {
+			*outer.borrow_mut() += 1;
+			lock.with_lock(|inner| {
+				*inner.borrow_mut() *= 2;
+				*inner.borrow()
+			})
+		});
+		assert_eq!(result, 6);
+		assert_eq!(lock.with_lock(|s| *s.borrow()), 6);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_new_shared() {
+		let lock = WithLock::new_shared(0);
+		let cell = MutexCell::new_shared(0);
+		let handles: Vec<_> = (0..4)
+			.map(|_| {
+				let lock = Arc::clone(&lock);
+				let cell = Arc::clone(&cell);
+				thread::spawn(move || {
+					lock.with_lock(|s| *s += 1);
+					cell.update(|s| *s += 2);
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		assert_eq!(lock.with_lock(|s| *s), 4);
+		assert_eq!(cell.get(), 8);
+	}
+
+	#[test]
+	fn test_project() {
+		let lock = WithLock::<SharedData>::new(SharedData { a: 1, b: 2 });
+		let a = lock.project(|s| &mut s.a);
+		a.with_lock(|a| *a += 10);
+		assert_eq!(a.with_lock(|a| *a), 11);
+		assert_eq!(lock.with_lock(|s| (s.a, s.b)), (11, 2));
+	}
+
+	#[test]
+	fn test_rw_lock_upgradable_read() {
+		let lock = WithRwLock::new(2);
+		let upgraded = lock.upgradable_read_with(|s| {
+			assert!(!s.is_upgraded());
+			if **s < 5 {
+				*s.upgrade() += 10;
+			}
+			assert_eq!(**s, 12);
+			s.is_upgraded()
+		});
+		assert!(upgraded);
+		assert_eq!(lock.upgradable_read_with(|s| **s), 12);
+	}
+
+	#[test]
+	fn test_rw_lock_upgradable_read_with_reader() {
+		let lock = Arc::new(WithRwLock::new(2));
+		let (locked_tx, locked_rx) = mpsc::channel();
+		let (release_tx, release_rx) = mpsc::channel::<()>();
+		let reader = {
+			let lock = Arc::clone(&lock);
+			thread::spawn(move || {
+				lock.read_with(|s| {
+					locked_tx.send(()).unwrap();
+					release_rx.recv().unwrap();
+					*s
+				})
+			})
+		};
+		locked_rx.recv().unwrap();
+		lock.upgradable_read_with(|s| {
+			// reading alongside the other reader is fine
+			assert_eq!(**s, 2);
+			release_tx.send(()).unwrap();
+			// upgrading waits for the reader to finish
+			*s.upgrade() = 3;
+		});
+		assert_eq!(reader.join().unwrap(), 2);
+		assert_eq!(lock.read_with(|s| *s), 3);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_into_arc() {
+		let lock = WithLock::<i64>::new(1).into_arc();
+		let cell = MutexCell::new(1).into_arc();
+		let handle = {
+			let lock = Arc::clone(&lock);
+			let cell = Arc::clone(&cell);
+			thread::spawn(move || {
+				lock.with_lock(|s| *s += 1);
+				cell.update(|s| *s += 1);
+			})
+		};
+		handle.join().unwrap();
+		assert_eq!(lock.with_lock(|s| *s), 2);
+		assert_eq!(cell.get(), 2);
+	}
+
+	#[test]
+	fn test_move_into_thread() {
+		let lock = WithLock::<Vec<i64>>::new(vec![1]);
+		let cell = MutexCell::new(String::from("a"));
+		let rw_lock = WithRwLock::new(1);
+		let (lock, cell, rw_lock) = thread::spawn(move || {
+			lock.with_lock(|s| s.push(2));
+			cell.update(|s| s.push('b'));
+			rw_lock.write_with(|s| *s += 1);
+			(lock, cell, rw_lock)
+		})
+		.join()
+		.unwrap();
+		assert_eq!(lock.with_lock(|s| s.clone()), vec![1, 2]);
+		assert_eq!(cell.into_inner(), "ab");
+		assert_eq!(rw_lock.read_with(|s| *s), 2);
+	}
+
+	#[test]
+	// Keeps the explicit deref this test was written with.
+	#[allow(clippy::explicit_auto_deref)]
+	fn test_with_lock_over_struct() {
+		let a = WithLock::<SharedData>::new(SharedData { a: 2, b: 2 });
+		let b = WithLock::<SharedData>::new(SharedData { a: 3, b: 3 });
+
+		let action_and_get = |s: &mut SharedData| (*s).a;
+		let a_lock = a.with_lock(action_and_get);
+		let b_lock = b.with_lock(action_and_get);
+		assert_eq!(a_lock + b_lock, 5);
+
+		// repeat action with embedded lambda expression and member b (avoid dead code warning)
+		let a_lock_2 = a.with_lock(|s| (*s).b);
+		let b_lock_2 = b.with_lock(|s| (*s).b);
+		assert_eq!(a_lock_2 + b_lock_2, 5);
+	}
+
+	#[test]
+	fn test_mutex_cell_no_deadlocks() {
+		let a = MutexCell::new(2);
+		let b = MutexCell::new(3);
+		let a_lock = a.get();
+		let b_lock = b.get();
+		assert_eq!(a_lock + b_lock, 5);
+		let a_lock_2 = a.get();
+		let b_lock_2 = b.get();
+		assert_eq!(a_lock_2 + b_lock_2, 5);
+	}
+
+	#[test]
+	fn test_mutex_cell_mutability() {
+		let cell = MutexCell::new(3);
+		assert_eq!(cell.get(), 3);
+		cell.set(4);
+		assert_eq!(cell.get(), 4);
+	}
+
+	#[test]
+	fn test_mutex_cell_replace() {
+		let cell = MutexCell::new(3);
+		assert_eq!(cell.replace(4), 3);
+		assert_eq!(cell.get(), 4);
+	}
+
+	#[test]
+	fn test_mutex_cell_replace_returns_previous() {
+		let cell = MutexCell::new(String::from("first"));
+		assert_eq!(cell.replace(String::from("second")), "first");
+		assert_eq!(cell.replace(String::from("third")), "second");
+		assert_eq!(cell.into_inner(), "third");
+	}
+
+	#[test]
+	fn test_mutex_cell_replace_with() {
+		let cell = MutexCell::new(3);
+		assert_eq!(cell.replace_with(|v| *v * 2), 3);
+		assert_eq!(cell.get(), 6);
+	}
+
+	#[test]
+	fn test_mutex_cell_swap() {
+		let c1 = MutexCell::new(5);
+		let c2 = MutexCell::new(10);
+		c1.swap(&c2);
+		assert_eq!(10, c1.get());
+		assert_eq!(5, c2.get());
+	}
+
+	#[test]
+	fn test_swap_slices() {
+		let front = [MutexCell::new(1), MutexCell::new(2), MutexCell::new(3)];
+		let back = [MutexCell::new(4), MutexCell::new(5), MutexCell::new(6)];
+		swap_slices(&front, &back);
+		assert_eq!(front.iter().map(|c| c.get()).collect::<Vec<_>>(), [4, 5, 6]);
+		assert_eq!(back.iter().map(|c| c.get()).collect::<Vec<_>>(), [1, 2, 3]);
+		swap_slices(&front, &front);
+		assert_eq!(front.iter().map(|c| c.get()).collect::<Vec<_>>(), [4, 5, 6]);
+	}
+
+	#[test]
+	#[should_panic(expected = "different lengths")]
+	fn test_swap_slices_length_mismatch() {
+		swap_slices(
+			&[MutexCell::new(1)],
+			&[MutexCell::new(2), MutexCell::new(3)],
+		);
+	}
+
+	#[test]
+	fn test_swap_slices_opposite_orders_dont_deadlock() {
+		let front: Arc<Vec<_>> = Arc::new((0..3).map(MutexCell::new).collect());
+		let back: Arc<Vec<_>> = Arc::new((3..6).map(MutexCell::new).collect());
+		let handles: Vec<_> = (0..4)
+			.map(|i| {
+				let front = Arc::clone(&front);
+				let back = Arc::clone(&back);
+				thread::spawn(move || {
+					for _ in 0..5_000 {
+						if i % 2 == 0 {
+							swap_slices(&front, &back);
+						} else {
+							swap_slices(&back, &front);
+						}
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		let mut values: Vec<_> = front.iter().chain(back.iter()).map(|c| c.get()).collect();
+		values.sort_unstable();
+		assert_eq!(values, [0, 1, 2, 3, 4, 5]);
+	}
+
+	#[test]
+	fn test_fold_cells() {
+		let cells: Vec<_> = (1..=4).map(MutexCell::new).collect();
+		assert_eq!(fold_cells(&cells, 0, |sum, value| sum + value), 10);
+		assert_eq!(fold_cells(&cells[..0], 7, |sum, value| sum + value), 7);
+		let words = [
+			MutexCell::new(String::from("a")),
+			MutexCell::new(String::from("b")),
+		];
+		assert_eq!(
+			fold_cells(&words, String::new(), |acc, word| acc + word),
+			"ab"
+		);
+		assert!(cells.iter().all(|c| !c.is_locked()));
+	}
+
+	#[test]
+	fn test_mutex_cell_try_swap() {
+		let a = MutexCell::new(1);
+		let b = MutexCell::new(2);
+		assert!(a.try_swap(&b));
+		assert_eq!((a.get(), b.get()), (2, 1));
+		assert!(!a.try_swap(&a));
+		assert_eq!(a.get(), 2);
+	}
+
+	#[test]
+	fn test_mutex_cell_try_swap_contended() {
+		let a = Arc::new(MutexCell::new(1));
+		let b = MutexCell::new(2);
+		let held = Arc::clone(&a);
+		let (forward, backward) = while_held(
+			move |wait| held.update(|_| wait()),
+			|| (a.try_swap(&b), b.try_swap(&a)),
+		);
+		assert!(!forward);
+		assert!(!backward);
+		assert_eq!((a.get(), b.get()), (1, 2));
+		assert!(!b.is_locked());
+	}
+
+	#[test]
+	fn test_mutex_cell_swap_doesnt_deadlock() {
+		let c1 = MutexCell::new(5);
+		assert_eq!(c1.get(), 5);
+		c1.swap(&c1);
+		assert_eq!(c1.get(), 5);
+	}
+
+	#[test]
+	fn test_mutex_cell_cross_swap_doesnt_deadlock() {
+		let a = Arc::new(MutexCell::new(1));
+		let b = Arc::new(MutexCell::new(2));
+		let handles: Vec<_> = (0..4)
+			.map(|i| {
+				let a = Arc::clone(&a);
+				let b = Arc::clone(&b);
+				thread::spawn(move || {
+					for _ in 0..10_000 {
+						if i % 2 == 0 {
+							a.swap(&b);
+						} else {
+							b.swap(&a);
+						}
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		let mut values = [a.get(), b.get()];
+		values.sort_unstable();
+		assert_eq!(values, [1, 2]);
+	}
+
+	#[test]
+	fn test_mutex_cell_swap_checked() {
+		let c1 = MutexCell::new(5);
+		let c2 = MutexCell::new(10);
+		assert!(c1.swap_checked(&c2));
+		assert_eq!(c1.get(), 10);
+		assert_eq!(c2.get(), 5);
+		assert!(!c1.swap_checked(&c1));
+		assert_eq!(c1.get(), 10);
+	}
+
+	#[test]
+	fn test_mutex_cell_move_into() {
+		let a = MutexCell::new(String::from("a"));
+		let b = MutexCell::new(String::from("b"));
+		assert_eq!(a.move_into(&b), "b");
+		assert_eq!(a.clone_inner(), "");
+		assert_eq!(b.clone_inner(), "a");
+		assert_eq!(b.move_into(&a), "");
+		assert_eq!(a.clone_inner(), "a");
+		assert_eq!(b.clone_inner(), "");
+		assert_eq!((a.generation(), b.generation()), (2, 2));
+	}
+
+	#[test]
+	fn test_mutex_cell_move_into_self() {
+		let a = MutexCell::new(5);
+		assert_eq!(a.move_into(&a), 0);
+		assert_eq!(a.get(), 5);
+		assert_eq!(a.generation(), 0);
+	}
+
+	#[test]
+	fn test_mutex_cell_get_mut() {
+		let mut c1 = MutexCell::new(5);
+		assert_eq!(c1.get(), 5);
+		let c2 = c1.get_mut();
+		*c2 += 1;
+		assert_eq!(c1.get(), 6);
+	}
+
+	#[test]
+	fn test_mutex_cell_get_mut_non_copy() {
+		let mut c = MutexCell::new(String::from("a"));
+		c.get_mut().push('b');
+		assert_eq!(c.clone_inner(), "ab");
+		c.get_mut().push('c');
+		assert_eq!(c.into_inner(), "abc");
+	}
+
+	#[test]
+	fn test_with_lock_replace() {
+		let a = WithLock::new(String::from("old"));
+		assert_eq!(a.replace(String::from("new")), "old");
+		assert_eq!(a.with_lock(|s| s.clone()), "new");
+	}
+
+	#[test]
+	fn test_with_lock_set_and_take() {
+		let a = WithLock::<i64>::new(1);
+		a.set(2);
+		assert_eq!(a.take(), 2);
+		assert_eq!(a.with_lock(|s| *s), 0);
+
+		let b = WithLock::new(String::from("a"));
+		b.set(String::from("b"));
+		assert_eq!(b.take(), "b");
+		assert_eq!(b.with_lock(|s| s.clone()), "");
+	}
+
+	#[test]
+	fn test_with_lock_map() {
+		let lock = WithLock::<i32>::new(41);
+		lock.with_lock(|s| *s += 1);
+		let lock: WithLock<String> = lock.map(|n| format!("n = {}", n));
+		lock.with_lock(|s| s.push('!'));
+		assert_eq!(lock.with_lock(|s| s.clone()), "n = 42!");
+	}
+
+	#[test]
+	fn test_with_lock_get_mut() {
+		let mut a = WithLock::<String>::new(String::from("a"));
+		a.get_mut().push('b');
+		assert_eq!(a.with_lock(|s| s.clone()), "ab");
+	}
+
+	#[test]
+	fn test_mutex_cell_as_ptr() {
+		let cell = MutexCell::new(5);
+		let ptr = cell.as_ptr();
+		let read = cell.map(|value| {
+			assert_eq!(ptr as *const i32, value as *const i32);
+			// SAFETY: the cell is locked by `map` for as long as this reference lives.
+			unsafe { *ptr }
+		});
+		assert_eq!(read, cell.get());
+	}
+
+	#[test]
+	fn test_mutex_cell_take() {
+		let c = MutexCell::new(5);
+		let five = c.take();
+
+		assert_eq!(five, 5);
+		assert_eq!(c.into_inner(), 0);
+	}
+
+	#[test]
+	fn test_mutex_cell_set_scoped() {
+		let c = MutexCell::new(1);
+		{
+			let _guard = c.set_scoped(2);
+			assert_eq!(c.get(), 2);
+			{
+				let _inner = c.set_scoped(3);
+				assert_eq!(c.get(), 3);
+			}
+			assert_eq!(c.get(), 2);
+		}
+		assert_eq!(c.get(), 1);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_set_scoped_restores_on_panic() {
+		let c = MutexCell::new(1);
+		let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+			let _guard = c.set_scoped(2);
+			panic!("oops")
+		}));
+		assert!(result.is_err());
+		assert_eq!(c.get(), 1);
+	}
+
+	#[test]
+	fn test_mutex_cell_take_or_else() {
+		struct NoDefault(i32);
+
+		let c = MutexCell::new(NoDefault(5));
+		let mut calls = 0;
+		let old = c.take_or_else(|| {
+			calls += 1;
+			NoDefault(calls)
+		});
+		assert_eq!(old.0, 5);
+		assert_eq!(calls, 1);
+		assert_eq!(c.into_inner().0, 1);
+	}
+
+	#[test]
+	fn test_mutex_cell_scope() {
+		let c = MutexCell::new((1, 2));
+		let sum = c.scope(|(a, b)| {
+			mem::swap(a, b);
+			*a += 10;
+			*a + *b
+		});
+		assert_eq!(sum, 13);
+		assert_eq!(c.get(), (12, 1));
+	}
+
+	#[test]
+	fn test_mutex_cell_scope_concurrent() {
+		let c = Arc::new(MutexCell::new((0, 0)));
+		let handles: Vec<_> = (0..8)
+			.map(|_| {
+				let c = Arc::clone(&c);
+				thread::spawn(move || {
+					for _ in 0..1000 {
+						c.scope(|(a, b)| {
+							assert_eq!(*a, *b);
+							*a += 1;
+							*b += 1;
+						});
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		assert_eq!(c.get(), (8000, 8000));
+	}
+
+	#[test]
+	fn test_mutex_cell_apply_all() {
+		use std::sync::atomic::{AtomicBool, Ordering};
+
+		type Triple = (u32, u32, u32);
+		let c = Arc::new(MutexCell::new((0, 0, 0)));
+		let done = Arc::new(AtomicBool::new(false));
+		let observer = {
+			let c = Arc::clone(&c);
+			let done = Arc::clone(&done);
+			thread::spawn(move || {
+				while !done.load(Ordering::Relaxed) {
+					let (a, b, c) = c.get();
+					assert!(a == b && b == c);
+				}
+			})
+		};
+		let mut steps: [fn(&mut Triple); 3] = [|s| s.0 += 1, |s| s.1 += 1, |s| s.2 += 1];
+		for _ in 0..1000 {
+			c.apply_all(&mut steps);
+		}
+		done.store(true, Ordering::Relaxed);
+		observer.join().unwrap();
+		assert_eq!(c.get(), (1000, 1000, 1000));
+	}
+
+	#[test]
+	fn test_mutex_cell_update() {
+		let c = MutexCell::new(5);
+		c.update(|v| *v *= 2);
+		assert_eq!(c.get(), 10);
+	}
+
+	#[test]
+	fn test_mutex_cell_update_concurrent() {
+		let c = Arc::new(MutexCell::new(0));
+		let handles: Vec<_> = (0..8)
+			.map(|_| {
+				let c = Arc::clone(&c);
+				thread::spawn(move || {
+					for _ in 0..1000 {
+						c.update(|v| *v += 1);
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		assert_eq!(c.get(), 8000);
+	}
+
+	#[test]
+	fn test_mutex_cell_update_and_get() {
+		let c = MutexCell::new(5);
+		assert_eq!(c.update_and_get(|v| *v += 1), 6);
+		assert_eq!(c.update_and_get(|v| *v *= 2), 12);
+		assert_eq!(c.get(), 12);
+	}
+
+	#[test]
+	fn test_mutex_cell_update_and_get_concurrent() {
+		let c = Arc::new(MutexCell::new(0));
+		let handles: Vec<_> = (0..8)
+			.map(|_| {
+				let c = Arc::clone(&c);
+				thread::spawn(move || {
+					(0..1000)
+						.map(|_| c.update_and_get(|v| *v += 1))
+						.collect::<Vec<_>>()
+				})
+			})
+			.collect();
+		let mut seen: Vec<_> = handles
+			.into_iter()
+			.flat_map(|handle| handle.join().unwrap())
+			.collect();
+		seen.sort_unstable();
+		assert_eq!(seen, (1..=8000).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn test_mutex_cell_modify_if() {
+		let c = MutexCell::new(5);
+		assert!(c.modify_if(|v| *v == 5, |v| *v = 6));
+		assert_eq!(c.get(), 6);
+	}
+
+	#[test]
+	fn test_mutex_cell_modify_if_unchanged() {
+		let c = MutexCell::new(5);
+		let mut ran = false;
+		assert!(!c.modify_if(|v| *v > 5, |_| ran = true));
+		assert!(!ran);
+		assert_eq!(c.get(), 5);
+	}
+
+	#[test]
+	fn test_mutex_cell_compare_and_swap() {
+		let c = MutexCell::new(5);
+		assert_eq!(c.compare_and_swap(5, 6), 5);
+		assert_eq!(c.get(), 6);
+	}
+
+	#[test]
+	fn test_mutex_cell_compare_and_swap_mismatch() {
+		let c = MutexCell::new(5);
+		assert_eq!(c.compare_and_swap(4, 6), 5);
+		assert_eq!(c.get(), 5);
+	}
+
+	#[test]
+	fn test_mutex_cell_try_compare_and_swap() {
+		let c = MutexCell::new(5);
+		assert_eq!(c.try_compare_and_swap(5, 6), Some(5));
+		assert_eq!(c.get(), 6);
+		assert_eq!(c.try_compare_and_swap(5, 7), Some(6));
+		assert_eq!(c.get(), 6);
+	}
+
+	#[test]
+	fn test_mutex_cell_try_compare_and_swap_contended() {
+		let c = Arc::new(MutexCell::new(5));
+		let held = Arc::clone(&c);
+		let result = while_held(
+			move |wait| held.update(|_| wait()),
+			|| c.try_compare_and_swap(5, 6),
+		);
+		assert_eq!(result, None);
+		assert_eq!(c.get(), 5);
+	}
+
+	#[test]
+	fn test_mutex_cell_fetch_add_sub() {
+		let c = MutexCell::new(5i64);
+		assert_eq!(c.fetch_add(3), 5);
+		assert_eq!(c.fetch_sub(10), 8);
+		assert_eq!(c.get(), -2);
+	}
+
+	#[test]
+	fn test_mutex_cell_fetch_add_sub_wrapping() {
+		use std::num::Wrapping;
+
+		let c = MutexCell::new(Wrapping(u8::MAX));
+		assert_eq!(c.fetch_add(Wrapping(2)), Wrapping(u8::MAX));
+		assert_eq!(c.get(), Wrapping(1));
+		assert_eq!(c.fetch_sub(Wrapping(2)), Wrapping(1));
+		assert_eq!(c.get(), Wrapping(u8::MAX));
+	}
+
+	#[test]
+	fn test_mutex_cell_fetch_add_concurrent() {
+		let c = Arc::new(MutexCell::new(0i64));
+		let handles: Vec<_> = (0..8)
+			.map(|i| {
+				let c = Arc::clone(&c);
+				thread::spawn(move || {
+					for _ in 0..1000 {
+						let _ = c.fetch_add(i + 2);
+						let _ = c.fetch_sub(1);
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		// Each thread adds a net `i + 1` a thousand times: 1000 * (1 + 2 + ... + 8).
+		assert_eq!(c.get(), 36_000);
+	}
+
+	#[test]
+	fn test_mutex_cell_fetch_min_max() {
+		let c = MutexCell::new(5);
+		assert_eq!(c.fetch_min(7), 5);
+		assert_eq!(c.fetch_min(2), 5);
+		assert_eq!(c.fetch_max(1), 2);
+		assert_eq!(c.fetch_max(9), 2);
+		assert_eq!(c.get(), 9);
+	}
+
+	#[test]
+	fn test_mutex_cell_fetch_min_max_concurrent() {
+		let min = Arc::new(MutexCell::new(u64::MAX));
+		let max = Arc::new(MutexCell::new(u64::MIN));
+		let handles: Vec<_> = (1..=8u64)
+			.map(|seed| {
+				let (min, max) = (Arc::clone(&min), Arc::clone(&max));
+				thread::spawn(move || {
+					// A small xorshift generator, so each thread feeds its own pseudo-random values.
+					let mut x = seed;
+					let mut seen = Vec::new();
+					for _ in 0..1000 {
+						x ^= x << 13;
+						x ^= x >> 7;
+						x ^= x << 17;
+						seen.push(x);
+						let _ = min.fetch_min(x);
+						let _ = max.fetch_max(x);
+					}
+					seen
+				})
+			})
+			.collect();
+		let seen: Vec<u64> = handles
+			.into_iter()
+			.flat_map(|handle| handle.join().unwrap())
+			.collect();
+		assert_eq!(min.get(), *seen.iter().min().unwrap());
+		assert_eq!(max.get(), *seen.iter().max().unwrap());
+	}
+
+	#[test]
+	fn test_debug() {
+		assert_eq!(
+			format!("{:?}", WithLock::<i64>::new(5)),
+			"WithLock { data: 5 }"
+		);
+		assert_eq!(format!("{:?}", MutexCell::new(5)), "MutexCell { data: 5 }");
+	}
+
+	#[test]
+	fn test_display() {
+		assert_eq!(MutexCell::new(5).to_string(), "5");
+		assert_eq!(format!("{:>4}", MutexCell::new("ab")), "  ab");
+		assert_eq!(format!("{:.1}", MutexCell::new(1.25)), "1.2");
+	}
+
+	#[test]
+	fn test_display_while_locked() {
+		let cell = Arc::new(MutexCell::new(5));
+		let held = Arc::clone(&cell);
+		let formatted = while_held(move |wait| held.update(|_| wait()), || cell.to_string());
+		assert_eq!(formatted, "<locked>");
+		assert_eq!(cell.to_string(), "5");
+	}
+
+	#[test]
+	fn test_debug_while_locked() {
+		let lock = Arc::new(WithLock::<i64>::new(5));
+		let held = Arc::clone(&lock);
+		let formatted = while_held(
+			move |wait| held.with_lock(|_| wait()),
+			|| format!("{:?}", lock),
+		);
+		assert_eq!(formatted, "WithLock { data: <locked> }");
+
+		let cell = Arc::new(MutexCell::new(5));
+		let held = Arc::clone(&cell);
+		let formatted = while_held(
+			move |wait| held.update(|_| wait()),
+			|| format!("{:?}", cell),
+		);
+		assert_eq!(formatted, "MutexCell { data: <locked> }");
+	}
+
+	#[test]
+	fn test_mutex_cell_clone() {
+		let original = MutexCell::new(5);
+		let clone = original.clone();
+		original.set(6);
+		assert_eq!(original.get(), 6);
+		assert_eq!(clone.get(), 5);
+	}
+
+	#[test]
+	fn test_default() {
+		#[derive(Default)]
+		struct Config {
+			name: String,
+			retries: u8,
+		}
+
+		assert_eq!(WithLock::<i64>::default().with_lock(|s| *s), 0);
+		assert_eq!(MutexCell::<i32>::default().get(), 0);
+
+		let config = MutexCell::<Config>::default().into_inner();
+		assert_eq!(config.name, "");
+		assert_eq!(config.retries, 0);
+	}
+
+	#[test]
+	fn test_from() {
+		assert_eq!(WithLock::from(5).with_lock(|s| *s), 5);
+		assert_eq!(MutexCell::from(5).get(), 5);
+
+		let lock: WithLock<i32> = 6.into();
+		assert_eq!(lock.with_lock(|s| *s), 6);
+		let cell: MutexCell<i32> = 6.into();
+		assert_eq!(cell.get(), 6);
+	}
+
+	#[cfg(feature = "serde")]
+	#[test]
+	fn test_serde_round_trip() {
+		let lock = WithLock::<Vec<i64>>::new(vec![1, 2, 3]);
+		let json = serde_json::to_string(&lock).unwrap();
+		assert_eq!(json, "[1,2,3]");
+		let lock: WithLock<Vec<i64>> = serde_json::from_str(&json).unwrap();
+		assert_eq!(lock.with_lock(|s| s.clone()), vec![1, 2, 3]);
+
+		let cell = MutexCell::new(5);
+		let json = serde_json::to_string(&cell).unwrap();
+		assert_eq!(json, "5");
+		let cell: MutexCell<i32> = serde_json::from_str(&json).unwrap();
+		assert_eq!(cell.get(), 5);
+	}
+
+	#[test]
+	fn test_mutex_cell_new_with() {
+		let mut calls = 0;
+		let cell = MutexCell::new_with(|| {
+			calls += 1;
+			String::from("built")
+		});
+		assert_eq!(calls, 1);
+		assert_eq!(cell.clone_inner(), "built");
+	}
+
+	#[test]
+	fn test_mutex_cell_try_lock_guard() {
+		let cell = Arc::new(MutexCell::new(1));
+		let held = Arc::clone(&cell);
+		let result = while_held(
+			move |wait| held.update(|_| wait()),
+			|| cell.try_lock_guard().is_none(),
+		);
+		assert!(result);
+		let generation = cell.generation();
+		{
+			let mut guard = cell.try_lock_guard().unwrap();
+			*guard += 1;
+			assert!(cell.try_lock_guard().is_none());
+		}
+		assert_eq!(cell.get(), 2);
+		assert_eq!(cell.generation(), generation + 1);
+	}
+
+	#[test]
+	fn test_mutex_cell_array() {
+		let cells: [MutexCell<usize>; 4] = MutexCell::array(|i| i + 1);
+		assert_eq!(
+			cells.iter().map(MutexCell::get).collect::<Vec<_>>(),
+			[1, 2, 3, 4]
+		);
+		// Each cell has its own lock.
+		cells[0].update(|_| cells[1].set(20));
+		assert_eq!(cells[1].get(), 20);
+		assert_eq!(cells[0].get(), 1);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_on_change() {
+		let cell = Arc::new(MutexCell::new(1));
+		let seen = Arc::new(MutexCell::new(Vec::new()));
+		{
+			let seen = Arc::clone(&seen);
+			cell.on_change(Box::new(move |v| seen.push(*v)));
+		}
+		cell.set(2);
+		assert_eq!(seen.clone_inner(), [2]);
+		cell.update(|v| *v += 1);
+		assert_eq!(cell.replace(10), 3);
+		assert_eq!(cell.take(), 10);
+		cell.scope(|v| *v = 20);
+		assert_eq!(seen.clone_inner(), [2, 3, 10, 0]);
+		assert_eq!(cell.clone().clone_inner(), 20);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_on_change_registry_is_lazy() {
+		let cell = MutexCell::new(1);
+		cell.set(2);
+		cell.update(|v| *v += 1);
+		assert!(cell.observers.get().is_none());
+
+		let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+		{
+			let calls = Arc::clone(&calls);
+			cell.on_change(Box::new(move |_| {
+				calls.fetch_add(1, Ordering::Relaxed);
+			}));
+		}
+		cell.set(4);
+		assert_eq!(calls.load(Ordering::Relaxed), 1);
+		assert_eq!(
+			cell.observers.get().map(|o| o.callbacks.lock().len()),
+			Some(1)
+		);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_on_change_reentrant() {
+		let cell = Arc::new(MutexCell::new(0));
+		let weak = Arc::downgrade(&cell);
+		cell.on_change(Box::new(move |v| {
+			let cell = weak.upgrade().unwrap();
+			// The cell is unlocked while callbacks run, so it can be changed again from here.
+			if *v < 3 {
+				cell.set(cell.get() + 1);
+			}
+		}));
+		cell.set(1);
+		assert_eq!(cell.get(), 3);
+
+		let name = String::from("borrowed");
+		let borrowed = MutexCell::new(name.as_str());
+		borrowed.on_change(Box::new(|v| assert_eq!(*v, "other")));
+		borrowed.set("other");
+	}
+
+	#[test]
+	fn test_mutex_cell_generation() {
+		let mut a = MutexCell::new(1);
+		let b = MutexCell::new(2);
+		assert_eq!(a.generation(), 0);
+		assert_eq!(a.get(), 1);
+		assert_eq!(a.map(|v| *v), 1);
+		assert_eq!(a.try_get(), Some(1));
+		assert_eq!(a.generation(), 0);
+
+		a.set(3);
+		assert_eq!(a.generation(), 1);
+		assert_eq!(a.replace(4), 3);
+		assert_eq!(a.generation(), 2);
+		a.update(|v| *v += 1);
+		assert_eq!(a.generation(), 3);
+		a.swap(&b);
+		assert_eq!((a.generation(), b.generation()), (4, 1));
+		*a.get_mut() += 1;
+		assert_eq!(a.generation(), 5);
+		let _ = a.fetch_add(1);
+		a.scope(|v| *v = 0);
+		assert_eq!(a.generation(), 7);
+		assert_eq!(a.get(), 0);
+		assert_eq!(a.generation(), 7);
+	}
+
+	#[test]
+	fn test_mutex_cell_get_if_changed() {
+		let cell = MutexCell::new(1);
+		let seen = cell.generation();
+		assert_eq!(cell.get_if_changed(seen), None);
+		cell.set(2);
+		let (value, seen) = cell.get_if_changed(seen).unwrap();
+		assert_eq!((value, seen), (2, 1));
+		assert_eq!(cell.get_if_changed(seen), None);
+		cell.update(|v| *v += 1);
+		assert_eq!(cell.get_if_changed(seen), Some((3, 2)));
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_get_after_panic() {
+		let cell = MutexCell::new(5);
+		let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+			cell.update(|s| {
+				*s = 6;
+				panic!("oops")
+			})
+		}));
+		assert!(result.is_err());
+		// The panic doesn't poison the cell, so the value it left behind is still readable.
+		assert_eq!(cell.get(), 6);
+	}
+
+	#[test]
+	fn test_mutex_cell_try_get() {
+		let cell = Arc::new(MutexCell::new(5));
+		assert_eq!(cell.try_get(), Some(5));
+		let held = Arc::clone(&cell);
+		let result = while_held(move |wait| held.update(|_| wait()), || cell.try_get());
+		assert_eq!(result, None);
+		assert_eq!(cell.try_get(), Some(5));
+	}
+
+	#[test]
+	fn test_mutex_cell_is_locked() {
+		let cell = Arc::new(MutexCell::new(5));
+		assert!(!cell.is_locked());
+		let held = Arc::clone(&cell);
+		assert!(while_held(
+			move |wait| held.update(|_| wait()),
+			|| cell.is_locked()
+		));
+		assert!(!cell.is_locked());
+	}
+
+	#[test]
+	fn test_mutex_cell_map() {
+		let cell = MutexCell::new(vec![1, 2, 3]);
+		assert_eq!(cell.map(|v| v.len()), 3);
+		assert_eq!(cell.map(|v| v.iter().sum::<i32>()), 6);
+	}
+
+	#[test]
+	fn test_mutex_cell_inspect() {
+		let cell = MutexCell::new(1);
+		let mut seen = Vec::new();
+		cell.inspect(|v| seen.push(*v)).set(2);
+		cell.inspect(|v| seen.push(*v)).update(|v| *v += 1);
+		assert_eq!(seen, [1, 2]);
+		assert_eq!(cell.inspect(|_| ()).get(), 3);
+	}
+
+	#[test]
+	fn test_mutex_cell_map_string() {
+		let cell = MutexCell::new(String::from("hello world"));
+		assert_eq!(cell.map(String::len), 11);
+		assert_eq!(cell.map(|s| s[6..].to_uppercase()), "WORLD");
+		assert_eq!(cell.map(|s| s.split(' ').count()), 2);
+	}
+
+	#[test]
+	fn test_mutex_cell_get_or_insert_with() {
+		let cell = MutexCell::new(None);
+		assert_eq!(cell.get_or_insert_with(|| String::from("a")), "a");
+		assert_eq!(cell.get_or_insert_with(|| String::from("b")), "a");
+	}
+
+	#[test]
+	fn test_mutex_cell_get_or_insert_with_runs_once() {
+		let cell = Arc::new(MutexCell::new(None));
+		let calls = Arc::new(MutexCell::new(0));
+		let handles: Vec<_> = (0..8)
+			.map(|i| {
+				let cell = Arc::clone(&cell);
+				let calls = Arc::clone(&calls);
+				thread::spawn(move || {
+					cell.get_or_insert_with(|| {
+						calls.update(|c| *c += 1);
+						i
+					})
+				})
+			})
+			.collect();
+		let values: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+		assert_eq!(calls.get(), 1);
+		assert!(values.iter().all(|v| *v == values[0]));
+	}
+
+	#[test]
+	fn test_static_initialization() {
+		COUNTER.update(|c| *c += 1);
+		assert_eq!(COUNTER.get(), 1);
+		LOCK.with_lock(|s| *s += 2);
+		assert_eq!(LOCK.with_lock(|s| *s), 2);
+	}
+
+	#[test]
+	fn test_mutex_cell_op_assign() {
+		let mut cell = MutexCell::new(10);
+		cell += 5;
+		assert_eq!(cell.get(), 15);
+		cell -= 3;
+		assert_eq!(cell.get(), 12);
+		cell *= 2;
+		assert_eq!(cell.get(), 24);
+		cell /= 4;
+		assert_eq!(cell.get(), 6);
+	}
+
+	#[test]
+	fn test_mutex_cell_op_assign_by_ref() {
+		let cell = MutexCell::new(10);
+		let mut shared = &cell;
+		shared += 5;
+		shared -= 3;
+		shared *= 2;
+		shared /= 4;
+		assert_eq!(cell.get(), 6);
+	}
+
+	#[test]
+	fn test_mutex_cell_add_assign_concurrent() {
+		let c = Arc::new(MutexCell::new(0));
+		let handles: Vec<_> = (0..8)
+			.map(|_| {
+				let c = Arc::clone(&c);
+				thread::spawn(move || {
+					for _ in 0..1000 {
+						let mut cell = &*c;
+						cell += 1;
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		assert_eq!(c.get(), 8000);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_vec() {
+		let stack = MutexCell::new(Vec::new());
+		assert!(stack.is_empty());
+		stack.push(1);
+		stack.push(2);
+		assert_eq!(stack.len(), 2);
+		assert_eq!(stack.pop(), Some(2));
+		assert_eq!(stack.pop(), Some(1));
+		assert_eq!(stack.pop(), None);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_vec_index() {
+		let cell = MutexCell::new(vec![1, 2, 3]);
+		assert_eq!(cell.get_index(1), Some(2));
+		assert_eq!(cell.get_index(3), None);
+		assert!(cell.set_index(1, 5));
+		assert!(!cell.set_index(3, 5));
+		assert_eq!(cell.clone_inner(), vec![1, 5, 3]);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_vec_index_concurrent() {
+		let cell = Arc::new(MutexCell::new(vec![0; 4]));
+		let handles: Vec<_> = (0..8)
+			.map(|i| {
+				let cell = Arc::clone(&cell);
+				thread::spawn(move || {
+					assert!(cell.set_index(i % 4, i));
+					assert!(cell.get_index(i % 4).is_some());
+					assert!(!cell.set_index(4 + i, i));
+					assert_eq!(cell.get_index(4 + i), None);
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		for (i, value) in cell.clone_inner().into_iter().enumerate() {
+			assert_eq!(value % 4, i);
+		}
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_vec_concurrent_push() {
+		let stack = Arc::new(MutexCell::new(Vec::new()));
+		let handles: Vec<_> = (0..8)
+			.map(|i| {
+				let stack = Arc::clone(&stack);
+				thread::spawn(move || {
+					for j in 0..100 {
+						stack.push(i * 100 + j);
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		assert_eq!(stack.len(), 800);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_vec_for_each() {
+		let cell = Arc::new(MutexCell::new(Vec::new()));
+		let pusher = {
+			let cell = Arc::clone(&cell);
+			thread::spawn(move || {
+				for i in 0..1000u64 {
+					cell.push(i);
+				}
+			})
+		};
+		while cell.len() < 1000 {
+			let (mut count, mut sum) = (0, 0);
+			cell.for_each(|v| {
+				count += 1;
+				sum += v;
+			});
+			// Pushes can't land mid-iteration, so the sum always matches a whole prefix.
+			assert_eq!(sum, count * (count.max(1) - 1) / 2);
+		}
+		pusher.join().unwrap();
+		let mut sum = 0;
+		cell.for_each(|v| sum += v);
+		assert_eq!(sum, 999 * 1000 / 2);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_vec_retain() {
+		let cell = MutexCell::new((1..=10).collect::<Vec<_>>());
+		cell.retain(|v| v % 2 == 0);
+		assert_eq!(cell.clone_inner(), [2, 4, 6, 8, 10]);
+		cell.retain(|_| false);
+		assert!(cell.is_empty());
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_vec_drain_all() {
+		let queue = Arc::new(MutexCell::new(Vec::new()));
+		let producers: Vec<_> = (0..4)
+			.map(|i| {
+				let queue = Arc::clone(&queue);
+				thread::spawn(move || {
+					for j in 0..50 {
+						queue.push(i * 50 + j);
+					}
+				})
+			})
+			.collect();
+		for producer in producers {
+			producer.join().unwrap();
+		}
+		let mut batch = queue.drain_all();
+		assert!(queue.is_empty());
+		batch.sort_unstable();
+		assert_eq!(batch, (0..200).collect::<Vec<_>>());
+		assert!(queue.drain_all().is_empty());
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_string() {
+		let log = MutexCell::new(String::new());
+		log.push_str("ab");
+		log.push_char('c');
+		assert_eq!(log.clone_inner(), "abc");
+		log.clear();
+		assert_eq!(log.clone_inner(), "");
+	}
+
+	#[test]
+	fn test_mutex_cell_duration() {
+		let total = MutexCell::new(Duration::ZERO);
+		total.add_duration(Duration::from_millis(5));
+		total.add_duration(Duration::from_millis(7));
+		assert_eq!(total.get(), Duration::from_millis(12));
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_duration_concurrent() {
+		let total = Arc::new(MutexCell::new(Duration::ZERO));
+		let handles: Vec<_> = (0..4)
+			.map(|_| {
+				let total = Arc::clone(&total);
+				thread::spawn(move || {
+					let mut own = Duration::ZERO;
+					for _ in 0..5 {
+						total.add_duration(Duration::from_millis(1));
+						let start = Instant::now();
+						thread::sleep(Duration::from_millis(2));
+						own += total.elapsed_since(start);
+					}
+					own
+				})
+			})
+			.collect();
+		let measured: Duration = handles.into_iter().map(|h| h.join().unwrap()).sum();
+		assert!(measured >= Duration::from_millis(4 * 5 * 2));
+		assert_eq!(total.get(), Duration::from_millis(4 * 5) + measured);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_string_concurrent() {
+		let log = Arc::new(MutexCell::new(String::new()));
+		let handles: Vec<_> = (0..8)
+			.map(|_| {
+				let log = Arc::clone(&log);
+				thread::spawn(move || {
+					for _ in 0..100 {
+						log.push_str("ab");
+						log.push_char('c');
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		let log = log.clone_inner();
+		assert_eq!(log.len(), 8 * 100 * 3);
+		assert_eq!(log.matches('c').count(), 800);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_hash_map() {
+		let registry = MutexCell::new(HashMap::new());
+		assert_eq!(registry.insert(String::from("a"), 1), None);
+		assert_eq!(registry.insert(String::from("a"), 2), Some(1));
+		assert_eq!(registry.get_cloned("a"), Some(2));
+		assert_eq!(registry.remove("a"), Some(2));
+		assert_eq!(registry.get_cloned("a"), None);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_hash_map_concurrent() {
+		let registry = Arc::new(MutexCell::new(HashMap::new()));
+		let handles: Vec<_> = (0..8u32)
+			.map(|i| {
+				let registry = Arc::clone(&registry);
+				thread::spawn(move || {
+					for j in 0..100 {
+						registry.insert(format!("{}-{}", i, j), j);
+					}
+					for j in (0..100).step_by(2) {
+						assert_eq!(registry.remove(&format!("{}-{}", i, j)), Some(j));
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		assert_eq!(registry.map(|m| m.len()), 400);
+		assert_eq!(registry.get_cloned("3-51"), Some(51));
+	}
+
+	#[test]
+	fn test_mutex_cell_fetch_update() {
+		let c = MutexCell::new(5);
+		assert_eq!(c.fetch_update(|v| Some(v * 2)), Ok(5));
+		assert_eq!(c.get(), 10);
+	}
+
+	#[test]
+	fn test_mutex_cell_fetch_update_rejected() {
+		let c = MutexCell::new(5u32);
+		assert_eq!(c.fetch_update(|v| v.checked_sub(6)), Err(5));
+		assert_eq!(c.get(), 5);
+	}
+
+	#[test]
+	fn test_mutex_cell_eq() {
+		let a = MutexCell::new(5);
+		let b = MutexCell::new(5);
+		let c = MutexCell::new(6);
+		assert_eq!(a, b);
+		assert_ne!(a, c);
+		assert_eq!(a, a);
+	}
+
+	#[test]
+	fn test_mutex_cell_eq_self_nan() {
+		let nan = MutexCell::new(f64::NAN);
+		assert_ne!(nan, nan);
+	}
+
+	#[test]
+	fn test_mutex_cell_ops() {
+		let a = MutexCell::new(6);
+		let b = MutexCell::new(2);
+		let sum = &a + &b;
+		assert_eq!(sum.get(), 8);
+		assert_eq!((&a - &b).get(), 4);
+		assert_eq!((&a * &b).get(), 12);
+		assert_eq!((&a + &a).get(), 12);
+		assert_eq!((&a * &a).get(), 36);
+		sum.set(0);
+		assert_eq!((a.get(), b.get()), (6, 2));
+	}
+
+	#[test]
+	fn test_mutex_cell_ord() {
+		let a = MutexCell::new(1);
+		let b = MutexCell::new(2);
+		assert!(a < b);
+		assert_eq!(a.cmp(&a), cmp::Ordering::Equal);
+		let nan = MutexCell::new(f64::NAN);
+		assert_eq!(nan.partial_cmp(&nan), None);
+		let mut cells: Vec<_> = [3, 1, 2].iter().copied().map(MutexCell::new).collect();
+		cells.sort();
+		assert_eq!(cells.iter().map(|c| c.get()).collect::<Vec<_>>(), [1, 2, 3]);
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	// The cells are never changed while in the set.
+	#[allow(clippy::mutable_key_type)]
+	fn test_mutex_cell_hash() {
+		use std::collections::HashSet;
+		let set: HashSet<_> = (0..3).map(MutexCell::new).collect();
+		assert!(set.contains(&MutexCell::new(1)));
+		assert!(!set.contains(&MutexCell::new(3)));
+		assert_eq!(set.len(), 3);
+	}
+
+	#[test]
+	fn test_mutex_cell_bool() {
+		let flag = MutexCell::new(false);
+		assert!(flag.toggle());
+		assert!(!flag.toggle());
+		assert!(flag.set_true());
+		assert!(!flag.set_true());
+		assert!(flag.set_false());
+		assert!(!flag.set_false());
+		assert!(!flag.get());
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_bool_on_change() {
+		let flag = MutexCell::new(false);
+		let (tx, rx) = mpsc::channel();
+		flag.on_change(Box::new(move |v| tx.send(*v).unwrap()));
+		flag.toggle();
+		flag.set_false();
+		flag.set_true();
+		flag.toggle();
+		assert_eq!(
+			rx.try_iter().collect::<Vec<_>>(),
+			[true, false, true, false]
+		);
+	}
+
+	#[test]
+	fn test_mutex_cell_toggle_concurrent() {
+		let flag = Arc::new(MutexCell::new(false));
+		let handles: Vec<_> = (0..7)
+			.map(|_| {
+				let flag = Arc::clone(&flag);
+				thread::spawn(move || {
+					for _ in 0..1001 {
+						flag.toggle();
+					}
+				})
+			})
+			.collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+		// 7 * 1001 toggles is odd, so the flag must end up flipped.
+		assert!(flag.get());
+	}
+
+	#[test]
+	fn test_mutex_cell_clone_inner() {
+		let cell = MutexCell::new(String::from("a"));
+		let snapshot = cell.clone_inner();
+		cell.update(|s| s.push('b'));
+		assert_eq!(snapshot, "a");
+		assert_eq!(cell.clone_inner(), "ab");
+	}
+
+	#[cfg(feature = "std")]
+	#[test]
+	fn test_mutex_cell_clone_inner_after_panic() {
+		let cell = MutexCell::new(String::from("a"));
+		let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+			cell.update(|s| {
+				s.push('b');
+				panic!("oops")
+			})
+		}));
+		assert!(result.is_err());
+		assert_eq!(cell.clone_inner(), "ab");
+	}
+
+	#[test]
+	fn test_mutex_cell_take_option() {
+		struct NoDefault(i32);
+
+		let c = MutexCell::new(Some(NoDefault(5)));
+		assert_eq!(c.take().map(|v| v.0), Some(5));
+		assert!(c.take().is_none());
+	}
+
+	#[test]
+	fn test_mutex_cell_into_inner() {
+		let c = MutexCell::new(5);
+		let five = c.into_inner();
+
+		assert_eq!(five, 5);
+	}
+}
+
+// Run with `RUSTFLAGS="--cfg loom" cargo test --release`.
+#[cfg(all(test, loom))]
+mod loom_tests {
+	use crate::*;
+	use loom::sync::Arc;
+	use loom::thread;
+
+	#[test]
+	fn swap_both_ways() {
+		loom::model(|| {
+			let a = Arc::new(MutexCell::new(1));
+			let b = Arc::new(MutexCell::new(2));
+			let handle = {
+				let (a, b) = (Arc::clone(&a), Arc::clone(&b));
+				thread::spawn(move || a.swap(&b))
+			};
+			b.swap(&a);
+			handle.join().unwrap();
+			// Two swaps cancel out, whichever runs first.
+			assert_eq!((a.get(), b.get()), (1, 2));
+			assert_eq!((a.generation(), b.generation()), (2, 2));
+		});
+	}
+
+	#[test]
+	fn lock2_opposite_orders() {
+		loom::model(|| {
+			let x = Arc::new(WithLock::new(0));
+			let y = Arc::new(WithLock::new(0));
+			let handle = {
+				let (x, y) = (Arc::clone(&x), Arc::clone(&y));
+				thread::spawn(move || {
+					lock2(&x, &y, |x, y| {
+						*x += 1;
+						*y -= 1;
+					})
+				})
+			};
+			lock2(&y, &x, |y, x| {
+				*x += 1;
+				*y -= 1;
+			});
+			handle.join().unwrap();
+			assert_eq!(lock2(&x, &y, |x, y| (*x, *y)), (2, -2));
+		});
+	}
+
+	#[test]
+	fn lock_many_overlapping_orders() {
+		loom::model(|| {
+			let locks = Arc::new([WithLock::new(0), WithLock::new(0), WithLock::new(0)]);
+			let handle = {
+				let locks = Arc::clone(&locks);
+				thread::spawn(move || {
+					lock_many(&[&locks[2], &locks[0], &locks[1]], |values| {
+						for value in values.iter_mut() {
+							**value += 1;
+						}
+					})
+				})
+			};
+			lock_many(&[&locks[1], &locks[2]], |values| {
+				for value in values.iter_mut() {
+					**value += 10;
+				}
+			});
+			handle.join().unwrap();
+			let values = lock_many(&[&locks[0], &locks[1], &locks[2]], |values| {
+				values.iter().map(|v| **v).collect::<Vec<_>>()
+			});
+			assert_eq!(values, [1, 11, 11]);
+		});
 	}
 }
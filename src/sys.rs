@@ -0,0 +1,78 @@
+//! The lock primitives backing this crate.
+//!
+//! With the `parking-lot` feature (the default) these come from `parking_lot`.
+//! With only `std` they are built on `std::sync`, see [`std_sync`].
+//! In the crate's own tests under `cfg(loom)`, the same wrappers are built on `loom::sync` instead, whatever the features, so loom can model them.
+//! Doctests and dependent crates never see that backend.
+//! Without `std` the crate is `no_std`, and spin locks from `spin` are used instead.
+
+#[cfg(all(loom, test, not(feature = "std")))]
+compile_error!("the loom tests require the `std` feature");
+
+#[cfg(all(feature = "parking-lot", not(all(loom, test))))]
+pub(crate) use parking_lot::{
+	const_mutex, const_rwlock, Mutex, MutexGuard, RwLock, RwLockUpgradableReadGuard,
+	RwLockWriteGuard,
+};
+
+#[cfg(all(feature = "parking-lot", not(all(loom, test))))]
+pub(crate) use parking_lot::{const_reentrant_mutex, Condvar, ReentrantMutex};
+
+#[cfg(all(feature = "std", any(all(loom, test), not(feature = "parking-lot"))))]
+mod std_sync;
+#[cfg(all(feature = "std", any(all(loom, test), not(feature = "parking-lot"))))]
+pub(crate) use std_sync::{
+	const_mutex, const_reentrant_mutex, const_rwlock, Condvar, Mutex, MutexGuard, ReentrantMutex,
+	RwLock, RwLockUpgradableReadGuard, RwLockWriteGuard,
+};
+
+#[cfg(not(feature = "std"))]
+pub(crate) use spin::mutex::{SpinMutex as Mutex, SpinMutexGuard as MutexGuard};
+#[cfg(not(feature = "std"))]
+pub(crate) use spin::{
+	RwLock, RwLockUpgradableGuard as RwLockUpgradableReadGuard, RwLockWriteGuard,
+};
+
+#[cfg(not(feature = "std"))]
+pub(crate) const fn const_mutex<T>(data: T) -> Mutex<T> {
+	Mutex::new(data)
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) const fn const_rwlock<T>(data: T) -> RwLock<T> {
+	RwLock::new(data)
+}
+
+/// Returns a raw pointer to the data protected by the mutex, without locking it.
+pub(crate) fn data_ptr<T>(lock: &Mutex<T>) -> *mut T {
+	#[cfg(feature = "std")]
+	return lock.data_ptr();
+	#[cfg(not(feature = "std"))]
+	return lock.as_mut_ptr();
+}
+
+/// Acquires an upgradable read lock, which can coexist with readers but not with writers or other upgradable readers.
+pub(crate) fn upgradable_read<T>(lock: &RwLock<T>) -> RwLockUpgradableReadGuard<'_, T> {
+	#[cfg(feature = "std")]
+	return lock.upgradable_read();
+	#[cfg(not(feature = "std"))]
+	return lock.upgradeable_read();
+}
+
+/// Atomically upgrades an upgradable read lock to a write lock, waiting for readers to leave.
+pub(crate) fn upgrade<T>(guard: RwLockUpgradableReadGuard<'_, T>) -> RwLockWriteGuard<'_, T> {
+	#[cfg(feature = "std")]
+	return RwLockUpgradableReadGuard::upgrade(guard);
+	#[cfg(not(feature = "std"))]
+	return guard.upgrade();
+}
+
+/// Unlocks the mutex, handing it straight to a waiting thread if there is one.
+///
+/// Only `parking_lot` supports this; the other backends simply unlock.
+pub(crate) fn unlock_fair<T>(guard: MutexGuard<'_, T>) {
+	#[cfg(all(feature = "parking-lot", not(all(loom, test))))]
+	MutexGuard::unlock_fair(guard);
+	#[cfg(any(not(feature = "parking-lot"), all(loom, test)))]
+	drop(guard);
+}
@@ -0,0 +1,458 @@
+//! Lock primitives built on `std::sync`, used when `std` is enabled but `parking-lot` is not.
+//!
+//! They mirror the parts of the `parking_lot` API this crate relies on.
+//! Poisoning is ignored: like `parking_lot`, a lock that was held during a panic can still be taken afterwards.
+//!
+//! In loom test builds they wrap `loom::sync` instead, and their constructors can no longer be `const`.
+
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::Ordering;
+#[cfg(all(loom, test))]
+use loom::sync;
+#[cfg(all(loom, test))]
+use loom::thread::{self, ThreadId};
+#[cfg(not(all(loom, test)))]
+use std::sync;
+use std::sync::{PoisonError, TryLockError};
+#[cfg(not(all(loom, test)))]
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+/// How long [`Mutex::try_lock_for`] sleeps between attempts at most.
+#[cfg(not(all(loom, test)))]
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A mutex whose data lives outside the `std::sync::Mutex`, so it can be pointed to without locking.
+pub(crate) struct Mutex<T> {
+	lock: sync::Mutex<()>,
+	// Set while a `MutexGuard` holds `lock`, so `is_locked` can answer without taking it.
+	locked: sync::atomic::AtomicBool,
+	data: UnsafeCell<T>,
+}
+
+// SAFETY: `data` is only reached through a `MutexGuard`, and `lock` makes sure there is at most one at a time.
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+const_fn! {
+	pub(crate) const fn const_mutex<T>(data: T) -> Mutex<T> {
+		Mutex {
+			lock: sync::Mutex::new(()),
+			locked: sync::atomic::AtomicBool::new(false),
+			data: UnsafeCell::new(data),
+		}
+	}
+}
+
+impl<T> Mutex<T> {
+	pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+		let lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+		MutexGuard::new(self, lock)
+	}
+
+	pub(crate) fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+		let lock = match self.lock.try_lock() {
+			Ok(lock) => lock,
+			Err(TryLockError::Poisoned(err)) => err.into_inner(),
+			Err(TryLockError::WouldBlock) => return None,
+		};
+		Some(MutexGuard::new(self, lock))
+	}
+
+	/// `std` has no timed lock, so this polls [`try_lock`](Mutex::try_lock) until the timeout runs out.
+	pub(crate) fn try_lock_for(&self, timeout: Duration) -> Option<MutexGuard<'_, T>> {
+		let deadline = match Instant::now().checked_add(timeout) {
+			Some(deadline) => deadline,
+			None => return Some(self.lock()),
+		};
+		loop {
+			if let Some(guard) = self.try_lock() {
+				return Some(guard);
+			}
+			let now = Instant::now();
+			if now >= deadline {
+				return None;
+			}
+			// loom has no sleep, but needs a yield to let the holder run.
+			#[cfg(all(loom, test))]
+			thread::yield_now();
+			#[cfg(not(all(loom, test)))]
+			thread::sleep((deadline - now).min(POLL_INTERVAL));
+		}
+	}
+
+	/// Reads the `locked` flag instead of trying the lock, which would make a concurrent `try_lock` fail spuriously.
+	pub(crate) fn is_locked(&self) -> bool {
+		self.locked.load(Ordering::Relaxed)
+	}
+
+	pub(crate) fn get_mut(&mut self) -> &mut T {
+		self.data.get_mut()
+	}
+
+	pub(crate) fn into_inner(self) -> T {
+		self.data.into_inner()
+	}
+
+	pub(crate) fn data_ptr(&self) -> *mut T {
+		self.data.get()
+	}
+}
+
+pub(crate) struct MutexGuard<'a, T> {
+	mutex: &'a Mutex<T>,
+	// Only `None` while `Condvar::wait` has handed the lock back.
+	lock: Option<sync::MutexGuard<'a, ()>>,
+	// Sharing the guard shares `&T`, so it may only be `Sync` when `T` is.
+	_marker: PhantomData<&'a mut T>,
+}
+
+impl<'a, T> MutexGuard<'a, T> {
+	fn new(mutex: &'a Mutex<T>, lock: sync::MutexGuard<'a, ()>) -> MutexGuard<'a, T> {
+		mutex.locked.store(true, Ordering::Relaxed);
+		MutexGuard {
+			mutex,
+			lock: Some(lock),
+			_marker: PhantomData,
+		}
+	}
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		// SAFETY: the guard holds the lock.
+		unsafe { &*self.mutex.data.get() }
+	}
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		// SAFETY: the guard holds the lock, and `&mut self` makes this the only borrow of it.
+		unsafe { &mut *self.mutex.data.get() }
+	}
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+	fn drop(&mut self) {
+		// Cleared while `lock` is still held, so it never stays set after the unlock.
+		self.mutex.locked.store(false, Ordering::Relaxed);
+	}
+}
+
+pub(crate) struct Condvar(sync::Condvar);
+
+impl Condvar {
+	const_fn! {
+		pub(crate) const fn new() -> Condvar {
+			Condvar(sync::Condvar::new())
+		}
+	}
+
+	/// Releases the guard's lock until notified, then takes it again.
+	pub(crate) fn wait<T>(&self, guard: &mut MutexGuard<'_, T>) {
+		let lock = guard.lock.take().expect("guard is locked");
+		guard.mutex.locked.store(false, Ordering::Relaxed);
+		guard.lock = Some(self.0.wait(lock).unwrap_or_else(PoisonError::into_inner));
+		guard.mutex.locked.store(true, Ordering::Relaxed);
+	}
+
+	pub(crate) fn notify_all(&self) {
+		self.0.notify_all();
+	}
+}
+
+/// A `std::sync::RwLock` with upgradable reads.
+///
+/// Writers and upgradable readers also hold `upgrade`, so an upgradable reader can swap its read lock for the write lock
+/// without a writer getting in between.
+pub(crate) struct RwLock<T> {
+	upgrade: sync::Mutex<()>,
+	lock: sync::RwLock<T>,
+}
+
+const_fn! {
+	pub(crate) const fn const_rwlock<T>(data: T) -> RwLock<T> {
+		RwLock {
+			upgrade: sync::Mutex::new(()),
+			lock: sync::RwLock::new(data),
+		}
+	}
+}
+
+impl<T> RwLock<T> {
+	pub(crate) fn read(&self) -> sync::RwLockReadGuard<'_, T> {
+		self.lock.read().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	pub(crate) fn write(&self) -> RwLockWriteGuard<'_, T> {
+		let upgrade = self.upgrade.lock().unwrap_or_else(PoisonError::into_inner);
+		RwLockWriteGuard {
+			lock: self.lock.write().unwrap_or_else(PoisonError::into_inner),
+			_upgrade: upgrade,
+		}
+	}
+
+	#[cfg(all(test, not(loom)))]
+	pub(crate) fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+		let upgrade = match self.upgrade.try_lock() {
+			Ok(upgrade) => upgrade,
+			Err(TryLockError::Poisoned(err)) => err.into_inner(),
+			Err(TryLockError::WouldBlock) => return None,
+		};
+		let lock = match self.lock.try_write() {
+			Ok(lock) => lock,
+			Err(TryLockError::Poisoned(err)) => err.into_inner(),
+			Err(TryLockError::WouldBlock) => return None,
+		};
+		Some(RwLockWriteGuard {
+			lock,
+			_upgrade: upgrade,
+		})
+	}
+
+	pub(crate) fn upgradable_read(&self) -> RwLockUpgradableReadGuard<'_, T> {
+		let upgrade = self.upgrade.lock().unwrap_or_else(PoisonError::into_inner);
+		RwLockUpgradableReadGuard {
+			rwlock: &self.lock,
+			lock: self.read(),
+			upgrade,
+		}
+	}
+}
+
+pub(crate) struct RwLockWriteGuard<'a, T> {
+	// Declared first so the write lock is released before `upgrade`.
+	lock: sync::RwLockWriteGuard<'a, T>,
+	_upgrade: sync::MutexGuard<'a, ()>,
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		&self.lock
+	}
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+	fn deref_mut(&mut self) -> &mut T {
+		&mut self.lock
+	}
+}
+
+pub(crate) struct RwLockUpgradableReadGuard<'a, T> {
+	rwlock: &'a sync::RwLock<T>,
+	lock: sync::RwLockReadGuard<'a, T>,
+	upgrade: sync::MutexGuard<'a, ()>,
+}
+
+impl<'a, T> RwLockUpgradableReadGuard<'a, T> {
+	/// Waits for the other readers to leave, then takes the write lock.
+	///
+	/// No writer can get in while the read lock is briefly released, since they all wait on `upgrade` first.
+	pub(crate) fn upgrade(guard: Self) -> RwLockWriteGuard<'a, T> {
+		let RwLockUpgradableReadGuard {
+			rwlock,
+			lock,
+			upgrade,
+		} = guard;
+		drop(lock);
+		RwLockWriteGuard {
+			lock: rwlock.write().unwrap_or_else(PoisonError::into_inner),
+			_upgrade: upgrade,
+		}
+	}
+}
+
+impl<T> Deref for RwLockUpgradableReadGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		&self.lock
+	}
+}
+
+/// A mutex the thread holding it can lock again, handing out shared references only.
+pub(crate) struct ReentrantMutex<T> {
+	owner: sync::Mutex<Owner>,
+	released: sync::Condvar,
+	data: T,
+}
+
+struct Owner {
+	thread: Option<ThreadId>,
+	count: usize,
+}
+
+// SAFETY: `data` is only reached through guards, and all live guards belong to the thread that owns the lock.
+unsafe impl<T: Send> Sync for ReentrantMutex<T> {}
+
+const_fn! {
+	pub(crate) const fn const_reentrant_mutex<T>(data: T) -> ReentrantMutex<T> {
+		ReentrantMutex {
+			owner: sync::Mutex::new(Owner {
+				thread: None,
+				count: 0,
+			}),
+			released: sync::Condvar::new(),
+			data,
+		}
+	}
+}
+
+impl<T> ReentrantMutex<T> {
+	pub(crate) fn lock(&self) -> ReentrantMutexGuard<'_, T> {
+		let current = thread::current().id();
+		let mut owner = self.owner.lock().unwrap_or_else(PoisonError::into_inner);
+		while owner.thread.is_some_and(|thread| thread != current) {
+			owner = self
+				.released
+				.wait(owner)
+				.unwrap_or_else(PoisonError::into_inner);
+		}
+		owner.thread = Some(current);
+		owner.count += 1;
+		ReentrantMutexGuard {
+			mutex: self,
+			_not_send: PhantomData,
+		}
+	}
+}
+
+pub(crate) struct ReentrantMutexGuard<'a, T> {
+	mutex: &'a ReentrantMutex<T>,
+	// Ownership is tied to the locking thread, so the guard must stay on it.
+	_not_send: PhantomData<*const ()>,
+}
+
+impl<T> Deref for ReentrantMutexGuard<'_, T> {
+	type Target = T;
+
+	fn deref(&self) -> &T {
+		&self.mutex.data
+	}
+}
+
+impl<T> Drop for ReentrantMutexGuard<'_, T> {
+	fn drop(&mut self) {
+		let mut owner = self
+			.mutex
+			.owner
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner);
+		owner.count -= 1;
+		if owner.count == 0 {
+			owner.thread = None;
+			drop(owner);
+			self.mutex.released.notify_one();
+		}
+	}
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+	use super::*;
+	use std::panic::{self, AssertUnwindSafe};
+	use std::sync::mpsc;
+
+	#[test]
+	fn test_mutex_ignores_poisoning() {
+		let mutex = const_mutex(1);
+		let result = panic::catch_unwind(AssertUnwindSafe(|| {
+			let mut guard = mutex.lock();
+			*guard = 2;
+			panic!("poison");
+		}));
+		assert!(result.is_err());
+		assert!(!mutex.is_locked());
+		assert_eq!(*mutex.try_lock().unwrap(), 2);
+		assert_eq!(*mutex.lock(), 2);
+	}
+
+	#[test]
+	fn test_mutex_try_lock_for() {
+		let mutex = const_mutex(0);
+		let guard = mutex.lock();
+		assert!(mutex.is_locked());
+		let start = Instant::now();
+		assert!(mutex.try_lock_for(Duration::from_millis(20)).is_none());
+		assert!(start.elapsed() >= Duration::from_millis(20));
+		drop(guard);
+		assert!(mutex.try_lock_for(Duration::from_millis(20)).is_some());
+		assert!(mutex.try_lock_for(Duration::MAX).is_some());
+	}
+
+	#[test]
+	fn test_is_locked_never_takes_the_lock() {
+		use std::sync::atomic::AtomicBool;
+
+		let mutex = const_mutex(0);
+		let done = AtomicBool::new(false);
+		thread::scope(|s| {
+			s.spawn(|| {
+				while !done.load(Ordering::Relaxed) {
+					let _ = mutex.is_locked();
+				}
+			});
+			// With a lone `try_lock` user, every attempt must succeed however often `is_locked` is polled.
+			for _ in 0..10_000 {
+				assert!(mutex.try_lock().is_some());
+			}
+			done.store(true, Ordering::Relaxed);
+		});
+		let guard = mutex.lock();
+		assert!(mutex.is_locked());
+		drop(guard);
+		assert!(!mutex.is_locked());
+	}
+
+	#[test]
+	fn test_condvar_wait_relocks() {
+		let mutex = const_mutex(false);
+		let condvar = Condvar::new();
+		thread::scope(|s| {
+			let mut guard = mutex.lock();
+			s.spawn(|| {
+				*mutex.lock() = true;
+				condvar.notify_all();
+			});
+			while !*guard {
+				condvar.wait(&mut guard);
+			}
+			assert!(mutex.is_locked());
+		});
+	}
+
+	#[test]
+	fn test_upgradable_read_blocks_writers() {
+		let lock = const_rwlock(0);
+		let guard = lock.upgradable_read();
+		assert_eq!(*lock.read(), 0);
+		assert!(lock.try_write().is_none());
+		let mut guard = RwLockUpgradableReadGuard::upgrade(guard);
+		*guard += 1;
+		drop(guard);
+		assert_eq!(*lock.try_write().unwrap(), 1);
+	}
+
+	#[test]
+	fn test_reentrant_mutex_excludes_other_threads() {
+		let mutex = const_reentrant_mutex(());
+		let outer = mutex.lock();
+		let inner = mutex.lock();
+		let (tx, rx) = mpsc::channel();
+		thread::scope(|s| {
+			s.spawn(|| {
+				let _guard = mutex.lock();
+				tx.send(()).unwrap();
+			});
+			drop(inner);
+			assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+			drop(outer);
+			assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+		});
+	}
+}
@@ -0,0 +1,8 @@
+// trybuild checks the ordinary build, not the loom one.
+#![cfg(not(loom))]
+
+#[test]
+fn compile_fail() {
+	let t = trybuild::TestCases::new();
+	t.compile_fail("tests/ui/*.rs");
+}
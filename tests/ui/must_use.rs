@@ -0,0 +1,12 @@
+#![deny(unused_must_use)]
+
+use with_lock::MutexCell;
+
+fn main() {
+	let cell = MutexCell::new(1);
+	cell.replace(2);
+	cell.take();
+	cell.fetch_add(1);
+	cell.get();
+	cell.into_inner();
+}
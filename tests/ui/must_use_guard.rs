@@ -0,0 +1,10 @@
+#![deny(unused_must_use)]
+
+use with_lock::{MutexCell, WithLock};
+
+fn main() {
+	let lock = WithLock::new(1);
+	lock.lock();
+	let cell = MutexCell::new(1);
+	cell.set_scoped(2);
+}
@@ -0,0 +1,5 @@
+use with_lock::WithLock;
+
+fn main() {
+	let _lock = WithLock::<String>::new(5i32);
+}